@@ -0,0 +1,614 @@
+use crate::crypto::{PrivateKey, PublicKey};
+use crate::encode::Encodable;
+use crate::error::{BtcError, Result};
+use crate::partial::{PartialTransaction, can_sign, unlocking_script};
+use crate::script::Script;
+use crate::types::{
+    LockTime, OutPoint, Sequence, SigHashType, Transaction, TransactionInput, TransactionOutput,
+};
+use rand::seq::SliceRandom;
+use std::cmp::Reverse;
+use uuid::Uuid;
+
+// give up on branch and bound after exploring this many branches
+const BNB_MAX_TRIES: usize = 100_000;
+// how often the fee is recalculated when the first estimate was short
+const MAX_FEE_ROUNDS: usize = 10;
+// a compact ecdsa signature followed by the sighash type byte
+const SIGNATURE_SIZE: usize = 65;
+
+/// How the builder picks the outputs to spend
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CoinSelection {
+    /// Spend the biggest outputs first, fewest inputs
+    LargestFirst,
+    /// Look for a combination that needs no change, falling back to
+    /// largest first when there is none
+    #[default]
+    BranchAndBound,
+    /// Pick at random, then keep adding outputs while that moves the
+    /// selection closer to twice the amount, so change is of useful size
+    RandomImprove,
+}
+
+// an output we can spend, valued after paying for its own input
+struct Candidate {
+    outpoint: OutPoint,
+    output: TransactionOutput,
+    effective_value: u64,
+    /// Unlocking script of the same size as the signed one
+    placeholder: Script,
+}
+
+/// Assembles a signed transaction paying `recipients` from a set of
+/// spendable outputs, sending whatever is left after the fee back as change.
+/// outputs paying to a held key, p2pk or p2pkh, can be spent and so can
+/// those locked with an added multisig script
+#[derive(Default)]
+pub struct TransactionBuilder {
+    recipients: Vec<(Script, u64)>,
+    utxos: Vec<(OutPoint, TransactionOutput)>,
+    keys: Vec<PrivateKey>,
+    multisigs: Vec<Script>,
+    change: Option<Script>,
+    /// Fee per byte of serialized transaction
+    fee_rate: u64,
+    coin_selection: CoinSelection,
+    sighash_type: SigHashType,
+    lock_time: LockTime,
+    sequence: Sequence,
+}
+
+impl TransactionBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// pay to the hash of `public_key`
+    pub fn add_recipient(self, public_key: PublicKey, value: u64) -> Self {
+        self.add_output(Script::p2pkh(&public_key), value)
+    }
+
+    /// pay to an arbitrary locking script
+    pub fn add_output(mut self, script_pubkey: Script, value: u64) -> Self {
+        self.recipients.push((script_pubkey, value));
+        self
+    }
+
+    /// outputs that may be spent, keyed the same way as the utxo set
+    pub fn add_utxos(
+        mut self,
+        utxos: impl IntoIterator<Item = (OutPoint, TransactionOutput)>,
+    ) -> Self {
+        self.utxos.extend(utxos);
+        self
+    }
+
+    /// keys used to sign the inputs, outputs without a key are never spent
+    pub fn add_key(mut self, key: PrivateKey) -> Self {
+        self.keys.push(key);
+        self
+    }
+
+    /// outputs locked to `required` of `public_keys` may be spent too. the
+    /// held keys among them sign, the rest is up to `build_partial`'s caller.
+    /// panics where `Script::multisig` does
+    pub fn add_multisig(mut self, required: usize, public_keys: &[PublicKey]) -> Self {
+        self.multisigs.push(Script::multisig(required, public_keys));
+        self
+    }
+
+    /// where change goes, by default back to the key or multisig script of
+    /// the first selected input
+    pub fn change_to(mut self, public_key: PublicKey) -> Self {
+        self.change = Some(Script::p2pkh(&public_key));
+        self
+    }
+
+    pub fn fee_rate(mut self, fee_rate: u64) -> Self {
+        self.fee_rate = fee_rate;
+        self
+    }
+
+    pub fn coin_selection(mut self, coin_selection: CoinSelection) -> Self {
+        self.coin_selection = coin_selection;
+        self
+    }
+
+    /// signature hash type used for every input, `SigHashType::ALL` by default
+    pub fn sighash_type(mut self, sighash_type: SigHashType) -> Self {
+        self.sighash_type = sighash_type;
+        self
+    }
+
+    /// inputs left final are made non-final, so the lock time is enforced
+    pub fn lock_time(mut self, lock_time: LockTime) -> Self {
+        self.lock_time = lock_time;
+        self
+    }
+
+    /// relative lock of every input, `Sequence::Final` by default
+    pub fn sequence(mut self, sequence: Sequence) -> Self {
+        self.sequence = sequence;
+        self
+    }
+
+    pub fn build(self) -> Result<Transaction> {
+        self.build_partial()?.finalize()
+    }
+
+    /// like `build`, but leaves the transaction open for more signatures,
+    /// which multisig inputs may need
+    pub fn build_partial(self) -> Result<PartialTransaction> {
+        if self.recipients.is_empty() || self.recipients.iter().any(|(_, value)| *value == 0) {
+            return Err(BtcError::InvalidTransaction);
+        }
+        let amount = self
+            .recipients
+            .iter()
+            .try_fold(0u64, |sum, (_, value)| sum.checked_add(*value))
+            .ok_or(BtcError::InvalidTransaction)?;
+        let recipient_outputs: Vec<TransactionOutput> = self
+            .recipients
+            .iter()
+            .map(|(script_pubkey, value)| new_output(script_pubkey.clone(), *value))
+            .collect();
+
+        let candidates = self.candidates();
+        if candidates.is_empty() {
+            return Err(BtcError::InsufficientFunds);
+        }
+        // everything but the inputs and change
+        let base_size = Transaction::new(vec![], recipient_outputs.clone()).size();
+        // the change script depends on the selection, which has to know
+        // what change costs. plan for the most expensive one
+        let max_cost_of_change = candidates
+            .iter()
+            .map(|candidate| self.cost_of_change(&self.change_script(&candidate.output)))
+            .max()
+            .unwrap_or_default();
+
+        let mut target = amount
+            .checked_add(self.fee_rate.saturating_mul(base_size as u64))
+            .ok_or(BtcError::InvalidTransaction)?;
+        for _ in 0..MAX_FEE_ROUNDS {
+            let selected = self.select(&candidates, target, max_cost_of_change)?;
+            let change_script = self.change_script(&candidates[selected[0]].output);
+            let cost_of_change = self.cost_of_change(&change_script);
+            let inputs: Vec<TransactionInput> = selected
+                .iter()
+                .map(|index| TransactionInput {
+                    previous_output: candidates[*index].outpoint,
+                    script_sig: candidates[*index].placeholder.clone(),
+                    sequence: self.input_sequence(),
+                })
+                .collect();
+            let input_value: u64 = selected.iter().map(|i| candidates[*i].output.value).sum();
+
+            let mut outputs = recipient_outputs.clone();
+            let without_change = Transaction::new(inputs.clone(), outputs.clone());
+            let fee = self.fee_rate.saturating_mul(without_change.size() as u64);
+            let needed = amount.saturating_add(fee);
+            let Some(excess) = input_value.checked_sub(needed) else {
+                // the estimate was short, try again asking for more
+                target = target.saturating_add(needed - input_value);
+                continue;
+            };
+            // change smaller than what it costs to spend is left to the miner
+            if excess > cost_of_change {
+                outputs.push(new_output(change_script, excess - cost_of_change));
+            }
+            let mut transaction = Transaction::new(inputs, outputs);
+            transaction.lock_time = self.lock_time;
+            return self.sign(transaction, &candidates, &selected);
+        }
+        Err(BtcError::InsufficientFunds)
+    }
+
+    // with every input final the lock time would be ignored. a relative
+    // lock of zero blocks locks nothing but is not final
+    fn input_sequence(&self) -> Sequence {
+        match (self.sequence, self.lock_time) {
+            (Sequence::Final, LockTime::Height(_) | LockTime::Time(_)) => Sequence::Blocks(0),
+            (sequence, _) => sequence,
+        }
+    }
+
+    // change goes back to where `spent` came from unless told otherwise
+    fn change_script(&self, spent: &TransactionOutput) -> Script {
+        if let Some(change) = &self.change {
+            return change.clone();
+        }
+        match self.signer(&spent.script_pubkey) {
+            Some(public_key) if spent.script_pubkey.multisig_keys().is_none() => {
+                Script::p2pkh(&public_key)
+            }
+            _ => spent.script_pubkey.clone(),
+        }
+    }
+
+    // fee for adding a change output paying to `change_script`
+    fn cost_of_change(&self, change_script: &Script) -> u64 {
+        let change_size = serialized_size(&new_output(change_script.clone(), u64::MAX));
+        self.fee_rate.saturating_mul(change_size as u64)
+    }
+
+    // spendable outputs sorted by value, biggest first
+    fn candidates(&self) -> Vec<Candidate> {
+        let mut candidates = vec![];
+        for (outpoint, output) in &self.utxos {
+            let Some(placeholder) = self.placeholder(&output.script_pubkey) else {
+                continue;
+            };
+            let input = TransactionInput {
+                previous_output: *outpoint,
+                script_sig: placeholder.clone(),
+                sequence: self.input_sequence(),
+            };
+            let input_fee = self.fee_rate.saturating_mul(serialized_size(&input) as u64);
+            // outputs worth less than spending them are dust
+            if output.value <= input_fee {
+                continue;
+            }
+            candidates.push(Candidate {
+                outpoint: *outpoint,
+                output: output.clone(),
+                effective_value: output.value - input_fee,
+                placeholder,
+            });
+        }
+        candidates.sort_by_key(|candidate| Reverse(candidate.effective_value));
+        candidates
+    }
+
+    fn select(
+        &self,
+        candidates: &[Candidate],
+        target: u64,
+        cost_of_change: u64,
+    ) -> Result<Vec<usize>> {
+        let values: Vec<u64> = candidates.iter().map(|c| c.effective_value).collect();
+        if values.iter().sum::<u64>() < target {
+            return Err(BtcError::InsufficientFunds);
+        }
+        let selected = match self.coin_selection {
+            CoinSelection::LargestFirst => largest_first(&values, target),
+            CoinSelection::BranchAndBound => branch_and_bound(&values, target, cost_of_change)
+                .or_else(|| largest_first(&values, target)),
+            CoinSelection::RandomImprove => random_improve(&values, target),
+        };
+        selected.ok_or(BtcError::InsufficientFunds)
+    }
+
+    // signatures are about the same size whatever they sign, so a placeholder
+    // lets the transaction be sized before its outputs are final
+    fn placeholder(&self, script_pubkey: &Script) -> Option<Script> {
+        let signature = vec![u8::MAX; SIGNATURE_SIZE];
+        let signatures: Vec<(PublicKey, Vec<u8>)> = if self.multisigs.contains(script_pubkey) {
+            let (required, public_keys) = script_pubkey.multisig_keys()?;
+            public_keys
+                .into_iter()
+                .take(required)
+                .map(|public_key| (public_key, signature.clone()))
+                .collect()
+        } else {
+            // multisig outputs are only spent when asked to
+            if script_pubkey.multisig_keys().is_some() {
+                return None;
+            }
+            vec![(self.signer(script_pubkey)?, signature)]
+        };
+        unlocking_script(script_pubkey, &signatures)
+    }
+
+    // the first held key that can sign for `script_pubkey`
+    fn signer(&self, script_pubkey: &Script) -> Option<PublicKey> {
+        self.keys
+            .iter()
+            .map(PrivateKey::public_key)
+            .find(|public_key| can_sign(script_pubkey, public_key))
+    }
+
+    fn sign(
+        &self,
+        mut transaction: Transaction,
+        candidates: &[Candidate],
+        selected: &[usize],
+    ) -> Result<PartialTransaction> {
+        // placeholders only stood in for sizing
+        for input in &mut transaction.inputs {
+            input.script_sig = Script::default();
+        }
+        let spent_outputs = selected
+            .iter()
+            .map(|index| candidates[*index].output.clone())
+            .collect();
+        let mut partial = PartialTransaction::new(transaction, spent_outputs, self.sighash_type)?;
+        for key in &self.keys {
+            partial.sign(key)?;
+        }
+        Ok(partial)
+    }
+}
+
+fn new_output(script_pubkey: Script, value: u64) -> TransactionOutput {
+    TransactionOutput {
+        value,
+        unique_id: Uuid::new_v4(),
+        script_pubkey,
+    }
+}
+
+fn serialized_size<T: Encodable>(value: &T) -> usize {
+    value.to_bytes().len()
+}
+
+// `values` are sorted from biggest to smallest
+fn largest_first(values: &[u64], target: u64) -> Option<Vec<usize>> {
+    let mut sum = 0;
+    let mut selected = vec![];
+    for (index, value) in values.iter().enumerate() {
+        if sum >= target {
+            break;
+        }
+        sum += value;
+        selected.push(index);
+    }
+    (sum >= target).then_some(selected)
+}
+
+// depth first search for the selection that overshoots `target` the least,
+// only accepting ones that overshoot by less than adding change would cost
+fn branch_and_bound(values: &[u64], target: u64, cost_of_change: u64) -> Option<Vec<usize>> {
+    struct Search<'a> {
+        values: &'a [u64],
+        target: u64,
+        upper: u64,
+        tries: usize,
+        selected: Vec<usize>,
+        best: Option<(Vec<usize>, u64)>,
+    }
+
+    impl Search<'_> {
+        fn explore(&mut self, index: usize, sum: u64, remaining: u64) {
+            if self.tries == 0 || self.best.as_ref().is_some_and(|(_, waste)| *waste == 0) {
+                return;
+            }
+            self.tries -= 1;
+            if sum > self.upper || sum + remaining < self.target {
+                return;
+            }
+            if sum >= self.target {
+                let waste = sum - self.target;
+                if self.best.as_ref().is_none_or(|(_, best)| waste < *best) {
+                    self.best = Some((self.selected.clone(), waste));
+                }
+                return;
+            }
+            let Some(value) = self.values.get(index).copied() else {
+                return;
+            };
+            self.selected.push(index);
+            self.explore(index + 1, sum + value, remaining - value);
+            self.selected.pop();
+            self.explore(index + 1, sum, remaining - value);
+        }
+    }
+
+    let mut search = Search {
+        values,
+        target,
+        upper: target.saturating_add(cost_of_change),
+        tries: BNB_MAX_TRIES,
+        selected: vec![],
+        best: None,
+    };
+    search.explore(0, 0, values.iter().sum());
+    search.best.map(|(selected, _)| selected)
+}
+
+fn random_improve(values: &[u64], target: u64) -> Option<Vec<usize>> {
+    let mut order: Vec<usize> = (0..values.len()).collect();
+    order.shuffle(&mut rand::thread_rng());
+    let mut order = order.into_iter();
+    let mut sum = 0;
+    let mut selected = vec![];
+    // random selection until the target is covered
+    for index in order.by_ref() {
+        sum += values[index];
+        selected.push(index);
+        if sum >= target {
+            break;
+        }
+    }
+    if sum < target {
+        return None;
+    }
+    // improve: aim for twice the target, never going over three times it
+    let ideal = target.saturating_mul(2);
+    let limit = target.saturating_mul(3);
+    for index in order {
+        let improved = sum + values[index];
+        if improved > limit || improved.abs_diff(ideal) >= sum.abs_diff(ideal) {
+            break;
+        }
+        sum = improved;
+        selected.push(index);
+    }
+    Some(selected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sha256::Hash;
+    use std::collections::HashSet;
+
+    fn utxo(public_key: &PublicKey, value: u64) -> (OutPoint, TransactionOutput) {
+        let outpoint = OutPoint::new(Hash::hash(&Uuid::new_v4()), 0);
+        (outpoint, new_output(Script::p2pkh(public_key), value))
+    }
+
+    fn output_total(transaction: &Transaction) -> u64 {
+        transaction.outputs.iter().map(|output| output.value).sum()
+    }
+
+    #[test]
+    fn largest_first_takes_the_biggest_outputs() {
+        assert_eq!(largest_first(&[50, 30, 20, 10], 60), Some(vec![0, 1]));
+        assert_eq!(largest_first(&[50, 30, 20, 10], 50), Some(vec![0]));
+        assert_eq!(largest_first(&[50, 30], 81), None);
+    }
+
+    #[test]
+    fn branch_and_bound_avoids_change() {
+        // 30 + 10 hits the target exactly, largest first would take 50
+        assert_eq!(branch_and_bound(&[50, 30, 20, 10], 40, 0), Some(vec![1, 3]));
+        // 50 overshoots by less than change would cost
+        assert_eq!(branch_and_bound(&[50, 30], 45, 5), Some(vec![0]));
+        // nothing lands within the window
+        assert_eq!(branch_and_bound(&[50, 30], 40, 5), None);
+        assert_eq!(branch_and_bound(&[50, 30], 81, 5), None);
+    }
+
+    #[test]
+    fn random_improve_aims_for_twice_the_target() {
+        let values = [10; 20];
+        for _ in 0..20 {
+            let selected = random_improve(&values, 25).unwrap();
+            let sum: u64 = selected.iter().map(|index| values[*index]).sum();
+            // closest to 50 without going past 75
+            assert_eq!(sum, 50);
+        }
+        assert_eq!(random_improve(&[10, 10], 21), None);
+    }
+
+    #[test]
+    fn insufficient_funds() {
+        let key = PrivateKey::new_key();
+        let result = TransactionBuilder::new()
+            .add_utxos([utxo(&key.public_key(), 1000)])
+            .add_key(key.clone())
+            .add_recipient(key.public_key(), 1001)
+            .build();
+        assert!(matches!(result, Err(BtcError::InsufficientFunds)));
+        // outputs without a key to sign them do not count
+        let result = TransactionBuilder::new()
+            .add_utxos([utxo(&PrivateKey::new_key().public_key(), 1000)])
+            .add_key(key.clone())
+            .add_recipient(key.public_key(), 10)
+            .build();
+        assert!(matches!(result, Err(BtcError::InsufficientFunds)));
+        // enough before the fee, not after
+        let result = TransactionBuilder::new()
+            .add_utxos([utxo(&key.public_key(), 1000)])
+            .add_key(key.clone())
+            .add_recipient(key.public_key(), 1000)
+            .fee_rate(1)
+            .build();
+        assert!(matches!(result, Err(BtcError::InsufficientFunds)));
+    }
+
+    #[test]
+    fn exact_match_needs_no_change() {
+        let key = PrivateKey::new_key();
+        let utxos = [
+            utxo(&key.public_key(), 50_000),
+            utxo(&key.public_key(), 30_000),
+            utxo(&key.public_key(), 10_000),
+        ];
+        let transaction = TransactionBuilder::new()
+            .add_utxos(utxos.clone())
+            .add_key(key.clone())
+            .add_recipient(PrivateKey::new_key().public_key(), 40_000)
+            .build()
+            .unwrap();
+        assert_eq!(transaction.outputs.len(), 1);
+        let spent: Vec<OutPoint> = transaction
+            .inputs
+            .iter()
+            .map(|input| input.previous_output)
+            .collect();
+        assert_eq!(spent, [utxos[1].0, utxos[2].0]);
+    }
+
+    #[test]
+    fn change_below_its_own_cost_goes_to_the_miner() {
+        let key = PrivateKey::new_key();
+        let recipient = PrivateKey::new_key().public_key();
+        let funds = utxo(&key.public_key(), 100_000);
+        let build = |amount| {
+            TransactionBuilder::new()
+                .add_utxos([funds.clone()])
+                .add_key(key.clone())
+                .add_recipient(recipient.clone(), amount)
+                .fee_rate(1)
+                .coin_selection(CoinSelection::LargestFirst)
+                .build()
+                .unwrap()
+        };
+        // with change the fee covers the change output as well
+        let transaction = build(1);
+        assert_eq!(transaction.outputs.len(), 2);
+        let fee = 100_000 - output_total(&transaction);
+        assert_eq!(fee, transaction.size() as u64);
+
+        // one more than the change would cost is kept
+        let transaction = build(100_000 - fee - 1);
+        assert_eq!(transaction.outputs.len(), 2);
+        assert_eq!(transaction.outputs[1].value, 1);
+        // anything less is left to the miner
+        let transaction = build(100_000 - fee);
+        assert_eq!(transaction.outputs.len(), 1);
+        assert_eq!(output_total(&transaction), 100_000 - fee);
+        let transaction = build(100_000 - fee + 1);
+        assert_eq!(transaction.outputs.len(), 1);
+    }
+
+    #[test]
+    fn change_goes_back_to_the_first_selected_input() {
+        let big = PrivateKey::new_key();
+        let small = PrivateKey::new_key();
+        let utxos = [
+            utxo(&big.public_key(), 1_000_000),
+            utxo(&small.public_key(), 100_000),
+        ];
+        let mut seen = HashSet::new();
+        // random improve picks either output alone, never both
+        for _ in 0..64 {
+            let transaction = TransactionBuilder::new()
+                .add_utxos(utxos.clone())
+                .add_key(big.clone())
+                .add_key(small.clone())
+                .add_recipient(PrivateKey::new_key().public_key(), 50_000)
+                .fee_rate(1)
+                .coin_selection(CoinSelection::RandomImprove)
+                .build()
+                .unwrap();
+            assert_eq!(transaction.inputs.len(), 1);
+            let spent = transaction.inputs[0].previous_output;
+            let owner = if spent == utxos[0].0 { &big } else { &small };
+            assert_eq!(
+                transaction.outputs[1].script_pubkey,
+                Script::p2pkh(&owner.public_key())
+            );
+            seen.insert(spent);
+        }
+        assert_eq!(seen.len(), 2);
+    }
+
+    #[test]
+    fn change_to_overrides_the_change_script() {
+        let key = PrivateKey::new_key();
+        let change = PrivateKey::new_key().public_key();
+        let transaction = TransactionBuilder::new()
+            .add_utxos([utxo(&key.public_key(), 100_000)])
+            .add_key(key.clone())
+            .add_recipient(key.public_key(), 50_000)
+            .change_to(change.clone())
+            .build()
+            .unwrap();
+        assert_eq!(transaction.outputs[1].script_pubkey, Script::p2pkh(&change));
+        assert_eq!(transaction.outputs[1].value, 50_000);
+    }
+}
@@ -31,11 +31,31 @@ impl Signature {
             .verify(&output_hash.as_bytes(), &self.0)
             .is_ok()
     }
+
+    /// fixed size r || s encoding, as pushed by scripts
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.0.to_bytes().to_vec()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        ECDSASignature::from_slice(bytes).ok().map(Signature)
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
 pub struct PublicKey(VerifyingKey<Secp256k1>);
 
+impl PublicKey {
+    /// compressed sec1 encoding, as pushed by scripts
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.0.to_encoded_point(true).as_bytes().to_vec()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        VerifyingKey::from_sec1_bytes(bytes).ok().map(PublicKey)
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct PrivateKey(#[serde(with = "signkey_serde")] pub SigningKey<Secp256k1>);
 
@@ -61,6 +81,6 @@ impl PrivateKey {
     }
 
     pub fn public_key(&self) -> PublicKey {
-        PublicKey(self.0.verifying_key().clone())
+        PublicKey(*self.0.verifying_key())
     }
 }
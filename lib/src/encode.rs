@@ -0,0 +1,740 @@
+use crate::crypto::PublicKey;
+use crate::error::{BtcError, Result};
+use crate::partial::PartialTransaction;
+use crate::script::{Op, Script};
+use crate::sha256::Hash;
+use crate::types::{
+    Block, BlockHeader, LockTime, OutPoint, Sequence, SigHashType, Transaction, TransactionInput,
+    TransactionOutput,
+};
+use crate::utils::{CompactTarget, MerkleRoot};
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+/// Written first by every block header and transaction, so the encoding
+/// can change later without old data being misread
+pub const ENCODING_VERSION: u32 = 1;
+
+// decoding never reserves more than this many items up front, the length
+// prefix is not trusted until the items are actually there
+const MAX_PREALLOCATION: usize = 1024;
+
+/// The canonical binary form hashes and signatures are computed over.
+/// Integers are little endian, lengths are bitcoin's compact sizes
+pub trait Encodable {
+    fn encode(&self, buf: &mut Vec<u8>);
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        self.encode(&mut buf);
+        buf
+    }
+}
+
+pub trait Decodable: Sized {
+    /// read one value from the front of `reader`, leaving the rest
+    fn decode(reader: &mut &[u8]) -> Result<Self>;
+
+    /// decode a value that has to take up all of `bytes`
+    fn from_bytes(mut bytes: &[u8]) -> Result<Self> {
+        let value = Self::decode(&mut bytes)?;
+        if !bytes.is_empty() {
+            return Err(BtcError::InvalidEncoding);
+        }
+        Ok(value)
+    }
+}
+
+fn take<'a>(reader: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if reader.len() < len {
+        return Err(BtcError::InvalidEncoding);
+    }
+    let (taken, rest) = reader.split_at(len);
+    *reader = rest;
+    Ok(taken)
+}
+
+fn take_array<const N: usize>(reader: &mut &[u8]) -> Result<[u8; N]> {
+    Ok(take(reader, N)?.try_into().unwrap())
+}
+
+macro_rules! impl_int {
+    ($($int:ty),*) => {
+        $(
+            impl Encodable for $int {
+                fn encode(&self, buf: &mut Vec<u8>) {
+                    buf.extend_from_slice(&self.to_le_bytes());
+                }
+            }
+
+            impl Decodable for $int {
+                fn decode(reader: &mut &[u8]) -> Result<Self> {
+                    take_array(reader).map(<$int>::from_le_bytes)
+                }
+            }
+        )*
+    };
+}
+
+impl_int!(u8, u16, u32, u64, i64);
+
+/// A length, in as few bytes as bitcoin's compact size allows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactSize(pub u64);
+
+impl Encodable for CompactSize {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self.0 {
+            0..0xfd => (self.0 as u8).encode(buf),
+            0xfd..=0xffff => {
+                0xfdu8.encode(buf);
+                (self.0 as u16).encode(buf);
+            }
+            0x1_0000..=0xffff_ffff => {
+                0xfeu8.encode(buf);
+                (self.0 as u32).encode(buf);
+            }
+            _ => {
+                0xffu8.encode(buf);
+                self.0.encode(buf);
+            }
+        }
+    }
+}
+
+impl Decodable for CompactSize {
+    fn decode(reader: &mut &[u8]) -> Result<Self> {
+        let (size, minimum) = match u8::decode(reader)? {
+            0xfd => (u16::decode(reader)? as u64, 0xfd),
+            0xfe => (u32::decode(reader)? as u64, 0x1_0000),
+            0xff => (u64::decode(reader)?, 0x1_0000_0000),
+            size => return Ok(CompactSize(size as u64)),
+        };
+        // there is only one encoding of every size
+        if size < minimum {
+            return Err(BtcError::InvalidEncoding);
+        }
+        Ok(CompactSize(size))
+    }
+}
+
+impl CompactSize {
+    fn decode_len(reader: &mut &[u8]) -> Result<usize> {
+        let CompactSize(len) = CompactSize::decode(reader)?;
+        usize::try_from(len).map_err(|_| BtcError::InvalidEncoding)
+    }
+}
+
+impl<T: Encodable> Encodable for [T] {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        CompactSize(self.len() as u64).encode(buf);
+        for item in self {
+            item.encode(buf);
+        }
+    }
+}
+
+impl<T: Encodable> Encodable for Vec<T> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.as_slice().encode(buf);
+    }
+}
+
+impl<T: Decodable> Decodable for Vec<T> {
+    fn decode(reader: &mut &[u8]) -> Result<Self> {
+        let len = CompactSize::decode_len(reader)?;
+        let mut items = Vec::with_capacity(len.min(MAX_PREALLOCATION));
+        for _ in 0..len {
+            items.push(T::decode(reader)?);
+        }
+        Ok(items)
+    }
+}
+
+/// fixed size, so without a length
+impl<T: Encodable, const N: usize> Encodable for [T; N] {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        for item in self {
+            item.encode(buf);
+        }
+    }
+}
+
+// raw bytes go in one piece instead of byte by byte
+fn encode_bytes(bytes: &[u8], buf: &mut Vec<u8>) {
+    CompactSize(bytes.len() as u64).encode(buf);
+    buf.extend_from_slice(bytes);
+}
+
+fn decode_bytes(reader: &mut &[u8]) -> Result<Vec<u8>> {
+    let len = CompactSize::decode_len(reader)?;
+    take(reader, len).map(<[u8]>::to_vec)
+}
+
+impl<A: Encodable, B: Encodable> Encodable for (A, B) {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.0.encode(buf);
+        self.1.encode(buf);
+    }
+}
+
+impl<A: Decodable, B: Decodable> Decodable for (A, B) {
+    fn decode(reader: &mut &[u8]) -> Result<Self> {
+        Ok((A::decode(reader)?, B::decode(reader)?))
+    }
+}
+
+impl Encodable for Hash {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.as_bytes());
+    }
+}
+
+impl Decodable for Hash {
+    fn decode(reader: &mut &[u8]) -> Result<Self> {
+        take_array(reader).map(Hash::from_bytes)
+    }
+}
+
+impl Encodable for MerkleRoot {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.hash().encode(buf);
+    }
+}
+
+impl Decodable for MerkleRoot {
+    fn decode(reader: &mut &[u8]) -> Result<Self> {
+        Hash::decode(reader).map(MerkleRoot::from_hash)
+    }
+}
+
+impl Encodable for CompactTarget {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.0.encode(buf);
+    }
+}
+
+impl Decodable for CompactTarget {
+    fn decode(reader: &mut &[u8]) -> Result<Self> {
+        u32::decode(reader).map(CompactTarget)
+    }
+}
+
+/// seconds since the unix epoch, then nanoseconds
+impl Encodable for DateTime<Utc> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.timestamp().encode(buf);
+        self.timestamp_subsec_nanos().encode(buf);
+    }
+}
+
+impl Decodable for DateTime<Utc> {
+    fn decode(reader: &mut &[u8]) -> Result<Self> {
+        let seconds = i64::decode(reader)?;
+        let nanos = u32::decode(reader)?;
+        DateTime::from_timestamp(seconds, nanos).ok_or(BtcError::InvalidEncoding)
+    }
+}
+
+impl Encodable for Uuid {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self.as_bytes());
+    }
+}
+
+impl Decodable for Uuid {
+    fn decode(reader: &mut &[u8]) -> Result<Self> {
+        take_array(reader).map(Uuid::from_bytes)
+    }
+}
+
+/// compressed sec1, always 33 bytes
+impl Encodable for PublicKey {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.to_bytes());
+    }
+}
+
+impl Decodable for PublicKey {
+    fn decode(reader: &mut &[u8]) -> Result<Self> {
+        let bytes: [u8; 33] = take_array(reader)?;
+        PublicKey::from_bytes(&bytes).ok_or(BtcError::InvalidEncoding)
+    }
+}
+
+// opcodes are bitcoin's where it has the same operation
+const OP_NUMBER: u8 = 0x01;
+const OP_PUSH: u8 = 0x4c;
+const OP_IF: u8 = 0x63;
+const OP_NOTIF: u8 = 0x64;
+const OP_ELSE: u8 = 0x67;
+const OP_ENDIF: u8 = 0x68;
+const OP_VERIFY: u8 = 0x69;
+const OP_RETURN: u8 = 0x6a;
+const OP_DROP: u8 = 0x75;
+const OP_DUP: u8 = 0x76;
+const OP_SWAP: u8 = 0x7c;
+const OP_EQUAL: u8 = 0x87;
+const OP_EQUALVERIFY: u8 = 0x88;
+const OP_SHA256: u8 = 0xa8;
+const OP_CHECKSIG: u8 = 0xac;
+const OP_CHECKSIGVERIFY: u8 = 0xad;
+const OP_CHECKMULTISIG: u8 = 0xae;
+const OP_CHECKMULTISIGVERIFY: u8 = 0xaf;
+const OP_CHECKLOCKTIMEVERIFY: u8 = 0xb1;
+const OP_CHECKSEQUENCEVERIFY: u8 = 0xb2;
+
+/// the opcode, followed by the data for pushes and numbers
+impl Encodable for Op {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        let opcode = match self {
+            Op::Push(data) => {
+                OP_PUSH.encode(buf);
+                return encode_bytes(data, buf);
+            }
+            Op::Number(number) => {
+                OP_NUMBER.encode(buf);
+                return number.encode(buf);
+            }
+            Op::Dup => OP_DUP,
+            Op::Drop => OP_DROP,
+            Op::Swap => OP_SWAP,
+            Op::If => OP_IF,
+            Op::NotIf => OP_NOTIF,
+            Op::Else => OP_ELSE,
+            Op::EndIf => OP_ENDIF,
+            Op::Verify => OP_VERIFY,
+            Op::Return => OP_RETURN,
+            Op::Equal => OP_EQUAL,
+            Op::EqualVerify => OP_EQUALVERIFY,
+            Op::Sha256 => OP_SHA256,
+            Op::CheckSig => OP_CHECKSIG,
+            Op::CheckSigVerify => OP_CHECKSIGVERIFY,
+            Op::CheckMultiSig => OP_CHECKMULTISIG,
+            Op::CheckMultiSigVerify => OP_CHECKMULTISIGVERIFY,
+            Op::CheckLockTimeVerify => OP_CHECKLOCKTIMEVERIFY,
+            Op::CheckSequenceVerify => OP_CHECKSEQUENCEVERIFY,
+        };
+        opcode.encode(buf);
+    }
+}
+
+impl Decodable for Op {
+    fn decode(reader: &mut &[u8]) -> Result<Self> {
+        Ok(match u8::decode(reader)? {
+            OP_PUSH => Op::Push(decode_bytes(reader)?),
+            OP_NUMBER => Op::Number(i64::decode(reader)?),
+            OP_DUP => Op::Dup,
+            OP_DROP => Op::Drop,
+            OP_SWAP => Op::Swap,
+            OP_IF => Op::If,
+            OP_NOTIF => Op::NotIf,
+            OP_ELSE => Op::Else,
+            OP_ENDIF => Op::EndIf,
+            OP_VERIFY => Op::Verify,
+            OP_RETURN => Op::Return,
+            OP_EQUAL => Op::Equal,
+            OP_EQUALVERIFY => Op::EqualVerify,
+            OP_SHA256 => Op::Sha256,
+            OP_CHECKSIG => Op::CheckSig,
+            OP_CHECKSIGVERIFY => Op::CheckSigVerify,
+            OP_CHECKMULTISIG => Op::CheckMultiSig,
+            OP_CHECKMULTISIGVERIFY => Op::CheckMultiSigVerify,
+            OP_CHECKLOCKTIMEVERIFY => Op::CheckLockTimeVerify,
+            OP_CHECKSEQUENCEVERIFY => Op::CheckSequenceVerify,
+            _ => return Err(BtcError::InvalidEncoding),
+        })
+    }
+}
+
+impl Encodable for Script {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.0.encode(buf);
+    }
+}
+
+impl Decodable for Script {
+    fn decode(reader: &mut &[u8]) -> Result<Self> {
+        Vec::decode(reader).map(Script)
+    }
+}
+
+/// a tag byte, then the height or time
+impl Encodable for LockTime {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            LockTime::None => 0u8.encode(buf),
+            LockTime::Height(height) => {
+                1u8.encode(buf);
+                height.encode(buf);
+            }
+            LockTime::Time(time) => {
+                2u8.encode(buf);
+                time.encode(buf);
+            }
+        }
+    }
+}
+
+impl Decodable for LockTime {
+    fn decode(reader: &mut &[u8]) -> Result<Self> {
+        match u8::decode(reader)? {
+            0 => Ok(LockTime::None),
+            1 => u64::decode(reader).map(LockTime::Height),
+            2 => DateTime::decode(reader).map(LockTime::Time),
+            _ => Err(BtcError::InvalidEncoding),
+        }
+    }
+}
+
+/// a tag byte, then the number of blocks or seconds
+impl Encodable for Sequence {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Sequence::Final => 0u8.encode(buf),
+            Sequence::Blocks(blocks) => {
+                1u8.encode(buf);
+                blocks.encode(buf);
+            }
+            Sequence::Seconds(seconds) => {
+                2u8.encode(buf);
+                seconds.encode(buf);
+            }
+        }
+    }
+}
+
+impl Decodable for Sequence {
+    fn decode(reader: &mut &[u8]) -> Result<Self> {
+        match u8::decode(reader)? {
+            0 => Ok(Sequence::Final),
+            1 => u32::decode(reader).map(Sequence::Blocks),
+            2 => u32::decode(reader).map(Sequence::Seconds),
+            _ => Err(BtcError::InvalidEncoding),
+        }
+    }
+}
+
+impl Encodable for OutPoint {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.txid.encode(buf);
+        self.vout.encode(buf);
+    }
+}
+
+impl Decodable for OutPoint {
+    fn decode(reader: &mut &[u8]) -> Result<Self> {
+        Ok(OutPoint {
+            txid: Hash::decode(reader)?,
+            vout: u32::decode(reader)?,
+        })
+    }
+}
+
+impl Encodable for TransactionInput {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.previous_output.encode(buf);
+        self.script_sig.encode(buf);
+        self.sequence.encode(buf);
+    }
+}
+
+impl Decodable for TransactionInput {
+    fn decode(reader: &mut &[u8]) -> Result<Self> {
+        Ok(TransactionInput {
+            previous_output: OutPoint::decode(reader)?,
+            script_sig: Script::decode(reader)?,
+            sequence: Sequence::decode(reader)?,
+        })
+    }
+}
+
+impl Encodable for TransactionOutput {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.value.encode(buf);
+        self.unique_id.encode(buf);
+        self.script_pubkey.encode(buf);
+    }
+}
+
+impl Decodable for TransactionOutput {
+    fn decode(reader: &mut &[u8]) -> Result<Self> {
+        Ok(TransactionOutput {
+            value: u64::decode(reader)?,
+            unique_id: Uuid::decode(reader)?,
+            script_pubkey: Script::decode(reader)?,
+        })
+    }
+}
+
+fn decode_version(reader: &mut &[u8]) -> Result<()> {
+    if u32::decode(reader)? != ENCODING_VERSION {
+        return Err(BtcError::InvalidEncoding);
+    }
+    Ok(())
+}
+
+impl Encodable for Transaction {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        ENCODING_VERSION.encode(buf);
+        self.inputs.encode(buf);
+        self.outputs.encode(buf);
+        self.lock_time.encode(buf);
+    }
+}
+
+impl Decodable for Transaction {
+    fn decode(reader: &mut &[u8]) -> Result<Self> {
+        decode_version(reader)?;
+        Ok(Transaction {
+            inputs: Vec::decode(reader)?,
+            outputs: Vec::decode(reader)?,
+            lock_time: LockTime::decode(reader)?,
+        })
+    }
+}
+
+impl Encodable for BlockHeader {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        ENCODING_VERSION.encode(buf);
+        self.timestamp.encode(buf);
+        self.nonce.encode(buf);
+        self.prev_block_hash.encode(buf);
+        self.markle_root.encode(buf);
+        self.bits.encode(buf);
+    }
+}
+
+impl Decodable for BlockHeader {
+    fn decode(reader: &mut &[u8]) -> Result<Self> {
+        decode_version(reader)?;
+        Ok(BlockHeader {
+            timestamp: DateTime::decode(reader)?,
+            nonce: u64::decode(reader)?,
+            prev_block_hash: Hash::decode(reader)?,
+            markle_root: MerkleRoot::decode(reader)?,
+            bits: CompactTarget::decode(reader)?,
+        })
+    }
+}
+
+impl Encodable for Block {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.header.encode(buf);
+        self.transactions.encode(buf);
+    }
+}
+
+impl Decodable for Block {
+    fn decode(reader: &mut &[u8]) -> Result<Self> {
+        Ok(Block {
+            header: BlockHeader::decode(reader)?,
+            transactions: Vec::decode(reader)?,
+        })
+    }
+}
+
+/// the byte appended to signatures
+impl Encodable for SigHashType {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.to_byte().encode(buf);
+    }
+}
+
+impl Decodable for SigHashType {
+    fn decode(reader: &mut &[u8]) -> Result<Self> {
+        SigHashType::from_byte(u8::decode(reader)?).ok_or(BtcError::InvalidEncoding)
+    }
+}
+
+impl Encodable for PartialTransaction {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.transaction.encode(buf);
+        self.spent_outputs.encode(buf);
+        self.sighash_type.encode(buf);
+        self.signatures.encode(buf);
+    }
+}
+
+impl Decodable for PartialTransaction {
+    fn decode(reader: &mut &[u8]) -> Result<Self> {
+        let transaction = Transaction::decode(reader)?;
+        let spent_outputs = Vec::decode(reader)?;
+        let sighash_type = SigHashType::decode(reader)?;
+        let signatures: Vec<Vec<(PublicKey, Vec<u8>)>> = Vec::decode(reader)?;
+        // one list of signatures and one spent output per input
+        let mut partial = PartialTransaction::new(transaction, spent_outputs, sighash_type)
+            .map_err(|_| BtcError::InvalidEncoding)?;
+        if signatures.len() != partial.signatures.len() {
+            return Err(BtcError::InvalidEncoding);
+        }
+        partial.signatures = signatures;
+        Ok(partial)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::PrivateKey;
+    use chrono::TimeZone;
+
+    fn transaction() -> Transaction {
+        let public_key = PrivateKey::new_key().public_key();
+        let mut transaction = Transaction::new(
+            vec![TransactionInput {
+                previous_output: OutPoint::new(Hash::hash(&7u64), 3),
+                script_sig: Script(vec![Op::Push(vec![1, 2, 3]), Op::Number(-5)]),
+                sequence: Sequence::Blocks(10),
+            }],
+            vec![TransactionOutput {
+                value: 50,
+                unique_id: Uuid::new_v4(),
+                script_pubkey: Script::p2pkh(&public_key),
+            }],
+        );
+        transaction.lock_time = LockTime::Height(100);
+        transaction
+    }
+
+    fn header() -> BlockHeader {
+        BlockHeader::new(
+            Utc.with_ymd_and_hms(2025, 1, 1, 0, 0, 0).unwrap(),
+            42,
+            Hash::hash(&1u64),
+            MerkleRoot::calculate(&[transaction()]),
+            CompactTarget(0x1f00_ffff),
+        )
+    }
+
+    // decoding and encoding again has to give back the same bytes
+    fn round_trip<T: Encodable + Decodable>(value: &T) -> T {
+        let bytes = value.to_bytes();
+        let decoded = T::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.to_bytes(), bytes);
+        decoded
+    }
+
+    #[test]
+    fn transaction_round_trips() {
+        let transaction = transaction();
+        let decoded = round_trip(&transaction);
+        assert_eq!(decoded.hash(), transaction.hash());
+        assert_eq!(decoded.lock_time, LockTime::Height(100));
+        assert_eq!(decoded.inputs[0].sequence, Sequence::Blocks(10));
+    }
+
+    #[test]
+    fn header_round_trips() {
+        let header = header();
+        let decoded = round_trip(&header);
+        assert_eq!(decoded.hash(), header.hash());
+        assert_eq!(decoded.bits, header.bits);
+        assert_eq!(decoded.timestamp, header.timestamp);
+    }
+
+    #[test]
+    fn block_round_trips() {
+        let block = Block::new(header(), vec![transaction(), transaction()]);
+        let decoded = round_trip(&block);
+        assert_eq!(decoded.hash(), block.hash());
+        assert_eq!(decoded.transactions.len(), 2);
+    }
+
+    #[test]
+    fn script_and_outpoint_round_trip() {
+        let script = Script(vec![
+            Op::If,
+            Op::Push(vec![0xab; 300]),
+            Op::Else,
+            Op::Number(i64::MIN),
+            Op::EndIf,
+            Op::CheckMultiSigVerify,
+        ]);
+        assert_eq!(round_trip(&script), script);
+        let outpoint = OutPoint::new(Hash::hash(&9u64), u32::MAX);
+        assert_eq!(round_trip(&outpoint), outpoint);
+    }
+
+    #[test]
+    fn partial_transaction_round_trips() {
+        let key = PrivateKey::new_key();
+        let transaction = transaction();
+        let spent_output = TransactionOutput {
+            value: 60,
+            unique_id: Uuid::new_v4(),
+            script_pubkey: Script::p2pkh(&key.public_key()),
+        };
+        let sighash_type = SigHashType::from_byte(0x83).unwrap();
+        let mut partial =
+            PartialTransaction::new(transaction, vec![spent_output], sighash_type).unwrap();
+        partial.sign(&key).unwrap();
+        let decoded = round_trip(&partial);
+        assert_eq!(decoded.sighash_type, sighash_type);
+        assert_eq!(decoded.signatures, partial.signatures);
+        assert!(decoded.is_complete());
+
+        // a list of signatures for an input that does not exist
+        partial.signatures.push(vec![]);
+        assert!(PartialTransaction::from_bytes(&partial.to_bytes()).is_err());
+    }
+
+    #[test]
+    fn compact_size_uses_the_fewest_bytes() {
+        for (size, len) in [
+            (0, 1),
+            (0xfc, 1),
+            (0xfd, 3),
+            (0xffff, 3),
+            (0x1_0000, 5),
+            (0x1_0000_0000, 9),
+        ] {
+            let bytes = CompactSize(size).to_bytes();
+            assert_eq!(bytes.len(), len);
+            assert_eq!(CompactSize::from_bytes(&bytes).unwrap(), CompactSize(size));
+        }
+    }
+
+    #[test]
+    fn non_minimal_compact_size_is_rejected() {
+        // 0xfc fits into one byte
+        assert!(CompactSize::from_bytes(&[0xfd, 0xfc, 0x00]).is_err());
+        assert!(CompactSize::from_bytes(&[0xfe, 0xff, 0xff, 0x00, 0x00]).is_err());
+        assert!(CompactSize::from_bytes(&[0xff, 0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn unknown_encoding_version_is_rejected() {
+        for mut bytes in [transaction().to_bytes(), header().to_bytes()] {
+            bytes[..4].copy_from_slice(&(ENCODING_VERSION + 1).to_le_bytes());
+            assert!(Transaction::from_bytes(&bytes).is_err());
+            assert!(BlockHeader::from_bytes(&bytes).is_err());
+        }
+    }
+
+    #[test]
+    fn trailing_bytes_are_rejected() {
+        let mut bytes = transaction().to_bytes();
+        bytes.push(0);
+        assert!(matches!(
+            Transaction::from_bytes(&bytes),
+            Err(BtcError::InvalidEncoding)
+        ));
+    }
+
+    #[test]
+    fn truncated_input_is_rejected() {
+        let bytes = Block::new(header(), vec![transaction()]).to_bytes();
+        for len in 0..bytes.len() {
+            assert!(Block::from_bytes(&bytes[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn unknown_opcode_is_rejected() {
+        assert!(Op::from_bytes(&[OP_CHECKSIG]).is_ok());
+        assert!(Op::from_bytes(&[0xff]).is_err());
+        // a script with one op, which is unknown
+        assert!(Script::from_bytes(&[1, 0x00]).is_err());
+    }
+}
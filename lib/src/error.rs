@@ -6,6 +6,16 @@ pub enum BtcError {
     InvalidTransaction,
     #[error("Invalid Block")]
     InvalidBlock,
+    #[error("Block is already known")]
+    BlockAlreadyKnown,
+    #[error("Parent of the block is unknown")]
+    UnknownParent,
+    #[error("Block target is not the expected one")]
+    WrongTarget,
+    #[error("Block hash does not meet its target")]
+    InsufficientProofOfWork,
+    #[error("Block contains a transaction twice")]
+    DuplicateTransaction,
     #[error("Invalid BlockHeader")]
     InvalidBlockHeader,
     #[error("Invalid TransactionOutput")]
@@ -24,6 +34,28 @@ pub enum BtcError {
     InvalidPublicKey,
     #[error("Invalid Signature")]
     InvalidSignature,
+    #[error("Mempool is full")]
+    MempoolFull,
+    #[error("Insufficient funds")]
+    InsufficientFunds,
+    #[error("Invalid Script")]
+    InvalidScript,
+    #[error("Transaction is time locked")]
+    TransactionLocked,
+    #[error("Coinbase output is not mature")]
+    ImmatureCoinbase,
+    #[error("Block timestamp is too far in the future")]
+    FutureBlock,
+    #[error("Block timestamp is not after the median time past")]
+    OldBlock,
+    #[error("Invalid Encoding")]
+    InvalidEncoding,
+    #[error("Invalid Network")]
+    InvalidNetwork,
+    #[error("Invalid Storage")]
+    InvalidStorage,
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
 }
 
 pub type Result<T> = std::result::Result<T, BtcError>;
@@ -1,31 +1,28 @@
 use core::fmt;
-use serde::{Deserialize, Serialize};
 use std::error::Error;
-use uint::construct_uint;
-construct_uint! {
-    #[derive(Serialize, Deserialize)]
-    pub struct U256(4);
+#[allow(clippy::manual_div_ceil, clippy::assign_op_pattern)]
+mod u256 {
+    use serde::{Deserialize, Serialize};
+    use uint::construct_uint;
+    construct_uint! {
+        #[derive(Serialize, Deserialize)]
+        pub struct U256(4);
+    }
 }
-// initial reward in bitcoin - multiply by 10^8 to get satoshis
-pub const INITIAL_REWARD: u64 = 50;
-// halving interval in blocks
-pub const HALVING_INTERVAL: u64 = 210;
-// ideal block time in seconds
-pub const IDEAL_BLOCK_TIME: u64 = 10;
-// minimum target
-pub const MINIMUM_TARGET: U256 = U256([
-    0xFFFF_FFFF_FFFF_FFFF,
-    0xFFFF_FFFF_FFFF_FFFF,
-    0xFFFF_FFFF_FFFF_FFFF,
-    0x0000_FFFF_FFFF_FFFF,
-]);
-// difficulty update intervals in blocks
-pub const DIFFICULTY_UPDATE_INTERVALS: u64 = 50;
-
+pub use u256::U256;
 
+pub mod builder;
+pub mod clock;
 pub mod crypto;
+pub mod encode;
 pub mod error;
+pub mod mempool;
+pub mod params;
+pub mod partial;
+pub mod rpc;
+pub mod script;
 pub mod sha256;
+pub mod storage;
 pub mod types;
 pub mod utils;
 pub mod signkey_serde {
@@ -0,0 +1,465 @@
+use crate::clock::Clock;
+use crate::error::{BtcError, Result};
+use crate::params::ChainParams;
+use crate::sha256::Hash;
+use crate::types::{Block, NextBlock, OutPoint, Transaction, Utxo};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+// default cap on the summed size of all pooled transactions, in bytes
+pub const DEFAULT_MEMPOOL_SIZE: usize = 5 * 1024 * 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MempoolEntry {
+    pub transaction: Transaction,
+    /// Fee paid by the transaction
+    pub fee: u64,
+    /// Serialized size of the transaction
+    pub size: usize,
+    /// When the transaction entered the mempool
+    pub time: DateTime<Utc>,
+}
+
+impl MempoolEntry {
+    /// compare fee per byte without going through floats
+    pub fn cmp_fee_rate(&self, other: &MempoolEntry) -> Ordering {
+        let left = self.fee as u128 * other.size as u128;
+        let right = other.fee as u128 * self.size as u128;
+        left.cmp(&right)
+    }
+}
+
+/// Transactions waiting to be mined.
+///
+/// Every entry is valid against the utxo set it was added with and no
+/// two entries spend the same output.
+#[derive(Debug, Clone)]
+pub struct Mempool {
+    entries: HashMap<Hash, MempoolEntry>,
+    // spent output -> hash of the pooled transaction spending it
+    spent_outputs: HashMap<OutPoint, Hash>,
+    max_size: usize,
+    total_size: usize,
+}
+
+impl Mempool {
+    pub fn new(max_size: usize) -> Self {
+        Mempool {
+            entries: HashMap::new(),
+            spent_outputs: HashMap::new(),
+            max_size,
+            total_size: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// summed serialized size of all entries
+    pub fn size(&self) -> usize {
+        self.total_size
+    }
+
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.entries.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &Hash) -> Option<&MempoolEntry> {
+        self.entries.get(hash)
+    }
+
+    pub fn transactions(&self) -> impl Iterator<Item = &Transaction> {
+        self.entries.values().map(|entry| &entry.transaction)
+    }
+
+    /// entries ordered from highest to lowest fee rate
+    pub fn sorted_by_fee_rate(&self) -> Vec<&MempoolEntry> {
+        let mut entries: Vec<&MempoolEntry> = self.entries.values().collect();
+        entries.sort_by(|a, b| b.cmp_fee_rate(a).then(a.time.cmp(&b.time)));
+        entries
+    }
+
+    /// validate a transaction for `next_block` and add it to the pool,
+    /// entered at the time `clock` tells. when the pool grows past its
+    /// size cap the entries with the lowest fee rate are evicted
+    pub fn add(
+        &mut self,
+        transaction: Transaction,
+        utxos: &HashMap<OutPoint, Utxo>,
+        next_block: NextBlock,
+        params: &ChainParams,
+        clock: &dyn Clock,
+    ) -> Result<()> {
+        let hash = transaction.hash();
+        if self.entries.contains_key(&hash) {
+            return Err(BtcError::InvalidTransaction);
+        }
+        let fee = transaction.verify(utxos, &next_block, params)?;
+        // reject double-spends against other pool entries
+        if transaction
+            .inputs
+            .iter()
+            .any(|input| self.spent_outputs.contains_key(&input.previous_output))
+        {
+            return Err(BtcError::InvalidTransaction);
+        }
+        let entry = MempoolEntry {
+            size: transaction.size(),
+            transaction,
+            fee,
+            time: clock.now(),
+        };
+        self.insert(hash, entry);
+        self.evict();
+        if !self.entries.contains_key(&hash) {
+            return Err(BtcError::MempoolFull);
+        }
+        Ok(())
+    }
+
+    pub fn remove(&mut self, hash: &Hash) -> Option<MempoolEntry> {
+        let entry = self.entries.remove(hash)?;
+        for input in &entry.transaction.inputs {
+            self.spent_outputs.remove(&input.previous_output);
+        }
+        self.total_size -= entry.size;
+        Some(entry)
+    }
+
+    /// drop everything a newly connected block confirmed or conflicts with
+    pub fn remove_for_block(&mut self, block: &Block) {
+        for transaction in &block.transactions {
+            self.remove(&transaction.hash());
+            for input in &transaction.inputs {
+                if let Some(conflict) = self.spent_outputs.get(&input.previous_output).copied() {
+                    self.remove(&conflict);
+                }
+            }
+        }
+    }
+
+    /// drop entries that are no longer valid against `utxos`, e.g. after
+    /// blocks were disconnected, or that are locked again
+    pub fn revalidate(
+        &mut self,
+        utxos: &HashMap<OutPoint, Utxo>,
+        next_block: NextBlock,
+        params: &ChainParams,
+    ) {
+        let invalid: Vec<Hash> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| {
+                entry.transaction.fee(utxos).is_err()
+                    || entry
+                        .transaction
+                        .check_locks(utxos, &next_block, params)
+                        .is_err()
+            })
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in invalid {
+            self.remove(&hash);
+        }
+    }
+
+    /// put transactions from disconnected blocks back into the pool,
+    /// skipping the coinbase and anything that is no longer valid
+    pub fn readmit<'a>(
+        &mut self,
+        transactions: impl IntoIterator<Item = &'a Transaction>,
+        utxos: &HashMap<OutPoint, Utxo>,
+        next_block: NextBlock,
+        params: &ChainParams,
+        clock: &dyn Clock,
+    ) {
+        for transaction in transactions {
+            if transaction.inputs.is_empty() {
+                continue;
+            }
+            let _ = self.add(transaction.clone(), utxos, next_block, params, clock);
+        }
+    }
+
+    fn insert(&mut self, hash: Hash, entry: MempoolEntry) {
+        for input in &entry.transaction.inputs {
+            self.spent_outputs.insert(input.previous_output, hash);
+        }
+        self.total_size += entry.size;
+        self.entries.insert(hash, entry);
+    }
+
+    fn evict(&mut self) {
+        while self.total_size > self.max_size {
+            let lowest = self
+                .entries
+                .iter()
+                .min_by(|(_, a), (_, b)| a.cmp_fee_rate(b).then(b.time.cmp(&a.time)))
+                .map(|(hash, _)| *hash);
+            match lowest {
+                Some(hash) => {
+                    self.remove(&hash);
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Self::new(DEFAULT_MEMPOOL_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::TransactionBuilder;
+    use crate::clock::FixedClock;
+    use crate::crypto::PrivateKey;
+    use crate::script::Script;
+    use crate::sha256::Hash;
+    use crate::types::{BlockHeader, TransactionOutput};
+    use crate::utils::{CompactTarget, MerkleRoot};
+    use chrono::Duration;
+    use uuid::Uuid;
+
+    struct Fixture {
+        key: PrivateKey,
+        utxos: HashMap<OutPoint, Utxo>,
+        params: ChainParams,
+        clock: FixedClock,
+    }
+
+    impl Fixture {
+        // `count` outputs of 100000 paying to the same key
+        fn new(count: usize) -> Self {
+            let key = PrivateKey::new_key();
+            let params = ChainParams::regtest();
+            let utxos = (0..count)
+                .map(|_| {
+                    let output = TransactionOutput {
+                        value: 100_000,
+                        unique_id: Uuid::new_v4(),
+                        script_pubkey: Script::p2pkh(&key.public_key()),
+                    };
+                    let utxo = Utxo {
+                        output,
+                        height: 0,
+                        time: params.genesis_timestamp,
+                        coinbase: false,
+                    };
+                    (OutPoint::new(Hash::hash(&Uuid::new_v4()), 0), utxo)
+                })
+                .collect();
+            let clock = FixedClock(params.genesis_timestamp);
+            Fixture {
+                key,
+                utxos,
+                params,
+                clock,
+            }
+        }
+
+        fn next_block(&self, height: u64) -> NextBlock {
+            NextBlock {
+                height,
+                prev_time: self.params.genesis_timestamp,
+            }
+        }
+
+        fn outpoints(&self) -> Vec<OutPoint> {
+            let mut outpoints: Vec<OutPoint> = self.utxos.keys().copied().collect();
+            outpoints.sort_by_key(|outpoint| outpoint.txid.to_string());
+            outpoints
+        }
+
+        fn spend(&self, outpoint: OutPoint, fee_rate: u64) -> Transaction {
+            TransactionBuilder::new()
+                .add_utxos([(outpoint, self.utxos[&outpoint].output.clone())])
+                .add_key(self.key.clone())
+                .add_recipient(PrivateKey::new_key().public_key(), 50_000)
+                .fee_rate(fee_rate)
+                .build()
+                .unwrap()
+        }
+
+        fn add(&self, mempool: &mut Mempool, transaction: &Transaction) -> Result<()> {
+            mempool.add(
+                transaction.clone(),
+                &self.utxos,
+                self.next_block(1),
+                &self.params,
+                &self.clock,
+            )
+        }
+    }
+
+    fn block_with(transactions: Vec<Transaction>) -> Block {
+        let header = BlockHeader::new(
+            Utc::now(),
+            0,
+            Hash::zero(),
+            MerkleRoot::calculate(&transactions),
+            CompactTarget::default(),
+        );
+        Block::new(header, transactions)
+    }
+
+    #[test]
+    fn entries_are_ordered_by_fee_rate_then_age() {
+        let mut fixture = Fixture::new(4);
+        let outpoints = fixture.outpoints();
+        let mut mempool = Mempool::default();
+        for (outpoint, fee_rate) in outpoints.iter().zip([1, 5, 3, 5]) {
+            let transaction = fixture.spend(*outpoint, fee_rate);
+            fixture.add(&mut mempool, &transaction).unwrap();
+            fixture.clock = FixedClock(fixture.clock.0 + Duration::seconds(1));
+        }
+        let sorted: Vec<OutPoint> = mempool
+            .sorted_by_fee_rate()
+            .iter()
+            .map(|entry| entry.transaction.inputs[0].previous_output)
+            .collect();
+        assert_eq!(
+            sorted,
+            [outpoints[1], outpoints[3], outpoints[2], outpoints[0]]
+        );
+    }
+
+    #[test]
+    fn entry_time_comes_from_the_clock() {
+        let fixture = Fixture::new(1);
+        let mut mempool = Mempool::default();
+        let transaction = fixture.spend(fixture.outpoints()[0], 1);
+        fixture.add(&mut mempool, &transaction).unwrap();
+        let entry = mempool.get(&transaction.hash()).unwrap();
+        assert_eq!(entry.time, fixture.params.genesis_timestamp);
+    }
+
+    #[test]
+    fn double_spends_are_rejected() {
+        let fixture = Fixture::new(1);
+        let outpoint = fixture.outpoints()[0];
+        let mut mempool = Mempool::default();
+        let transaction = fixture.spend(outpoint, 1);
+        fixture.add(&mut mempool, &transaction).unwrap();
+        assert!(fixture.add(&mut mempool, &transaction).is_err());
+        assert!(
+            fixture
+                .add(&mut mempool, &fixture.spend(outpoint, 2))
+                .is_err()
+        );
+        assert_eq!(mempool.len(), 1);
+    }
+
+    #[test]
+    fn lowest_fee_rate_is_evicted_when_full() {
+        let fixture = Fixture::new(4);
+        let outpoints = fixture.outpoints();
+        let low = fixture.spend(outpoints[0], 1);
+        let high = fixture.spend(outpoints[1], 5);
+        let medium = fixture.spend(outpoints[2], 3);
+        // room for two of them
+        let mut mempool = Mempool::new(high.size() + low.size().max(medium.size()));
+        fixture.add(&mut mempool, &low).unwrap();
+        fixture.add(&mut mempool, &high).unwrap();
+        fixture.add(&mut mempool, &medium).unwrap();
+        assert_eq!(mempool.len(), 2);
+        assert!(!mempool.contains(&low.hash()));
+        assert!(mempool.size() <= high.size() + low.size().max(medium.size()));
+        // the output of the evicted transaction can be spent again
+        assert!(!mempool.spent_outputs.contains_key(&outpoints[0]));
+
+        // a newcomer paying the least is turned away
+        let lowest = fixture.spend(outpoints[3], 1);
+        assert!(matches!(
+            fixture.add(&mut mempool, &lowest),
+            Err(BtcError::MempoolFull)
+        ));
+        assert!(mempool.contains(&high.hash()));
+        assert!(mempool.contains(&medium.hash()));
+    }
+
+    #[test]
+    fn block_removes_confirmed_and_conflicting_entries() {
+        let fixture = Fixture::new(3);
+        let outpoints = fixture.outpoints();
+        let mut mempool = Mempool::default();
+        let confirmed = fixture.spend(outpoints[0], 1);
+        let conflicting = fixture.spend(outpoints[1], 1);
+        let unrelated = fixture.spend(outpoints[2], 1);
+        for transaction in [&confirmed, &conflicting, &unrelated] {
+            fixture.add(&mut mempool, transaction).unwrap();
+        }
+        // the block spends the same output as `conflicting`
+        let block = block_with(vec![confirmed.clone(), fixture.spend(outpoints[1], 2)]);
+        mempool.remove_for_block(&block);
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.contains(&unrelated.hash()));
+        assert_eq!(mempool.size(), unrelated.size());
+        assert_eq!(mempool.spent_outputs.len(), 1);
+    }
+
+    #[test]
+    fn revalidate_drops_spent_and_locked_entries() {
+        let mut fixture = Fixture::new(3);
+        let outpoints = fixture.outpoints();
+        fixture.utxos.get_mut(&outpoints[2]).unwrap().coinbase = true;
+        let maturity = fixture.params.coinbase_maturity;
+        let mut mempool = Mempool::default();
+        let kept = fixture.spend(outpoints[0], 1);
+        let missing = fixture.spend(outpoints[1], 1);
+        let coinbase_spend = fixture.spend(outpoints[2], 1);
+        for transaction in [&kept, &missing] {
+            fixture.add(&mut mempool, transaction).unwrap();
+        }
+        mempool
+            .add(
+                coinbase_spend.clone(),
+                &fixture.utxos,
+                fixture.next_block(maturity),
+                &fixture.params,
+                &fixture.clock,
+            )
+            .unwrap();
+
+        // the input of `missing` is gone and the coinbase is immature again
+        fixture.utxos.remove(&outpoints[1]);
+        mempool.revalidate(
+            &fixture.utxos,
+            fixture.next_block(maturity - 1),
+            &fixture.params,
+        );
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.contains(&kept.hash()));
+    }
+
+    #[test]
+    fn readmit_skips_coinbase_and_invalid_transactions() {
+        let fixture = Fixture::new(2);
+        let outpoints = fixture.outpoints();
+        let valid = fixture.spend(outpoints[0], 1);
+        let coinbase = Transaction::new(vec![], valid.outputs.clone());
+        let mut invalid = fixture.spend(outpoints[1], 1);
+        invalid.outputs[0].value += 1;
+        let mut mempool = Mempool::default();
+        mempool.readmit(
+            [&coinbase, &valid, &invalid],
+            &fixture.utxos,
+            fixture.next_block(1),
+            &fixture.params,
+            &fixture.clock,
+        );
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.contains(&valid.hash()));
+    }
+}
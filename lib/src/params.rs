@@ -0,0 +1,363 @@
+use crate::U256;
+use crate::error::BtcError;
+use crate::script::{Op, Script};
+use crate::sha256::Hash;
+use crate::types::{Block, BlockHeader, Transaction, TransactionOutput};
+use crate::utils::{CompactTarget, MerkleRoot};
+use chrono::{DateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use uuid::Uuid;
+
+// carried by the genesis coinbase, which nobody can spend
+const GENESIS_MESSAGE: &str = "btclib genesis";
+
+// easiest target outside of regtest, about 65 thousand hashes per block
+const MINIMUM_TARGET: CompactTarget = CompactTarget(0x1f00_ffff);
+// every hash but one in 65 thousand meets it
+const REGTEST_MINIMUM_TARGET: CompactTarget = CompactTarget(0x2100_ffff);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Network {
+    Mainnet,
+    Testnet,
+    /// Local testing, blocks are found right away
+    Regtest,
+}
+
+/// Consensus rules and defaults of a network, every node on it has to
+/// use the same ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChainParams {
+    pub network: Network,
+    /// Starts every network message so nodes of different networks
+    /// never talk to each other
+    pub magic: [u8; 4],
+    pub default_port: u16,
+    pub default_rpc_port: u16,
+    /// Block reward before the first halving, in whole coins
+    pub initial_reward: u64,
+    /// Blocks between halvings of the reward
+    pub halving_interval: u64,
+    /// Seconds between blocks the difficulty aims for
+    pub ideal_block_time: u64,
+    /// Easiest target a block may have
+    pub minimum_target: U256,
+    /// Blocks between difficulty adjustments
+    pub difficulty_update_interval: u64,
+    /// Whether the difficulty is adjusted at all
+    pub retargeting: bool,
+    /// Blocks whose median timestamp a new block has to be younger than
+    pub median_time_span: usize,
+    /// Seconds a block timestamp may be ahead of our clock
+    pub max_future_block_time: u64,
+    /// Blocks a coinbase output has to be buried under before it is spent
+    pub coinbase_maturity: u64,
+    pub genesis_timestamp: DateTime<Utc>,
+    /// Found by mining the genesis block once, it has to meet the
+    /// minimum target
+    pub genesis_nonce: u64,
+}
+
+impl ChainParams {
+    pub fn mainnet() -> Self {
+        ChainParams {
+            network: Network::Mainnet,
+            magic: *b"BTCL",
+            default_port: 9000,
+            default_rpc_port: 9332,
+            initial_reward: 50,
+            halving_interval: 210,
+            ideal_block_time: 10,
+            minimum_target: MINIMUM_TARGET.to_target(),
+            difficulty_update_interval: 50,
+            retargeting: true,
+            median_time_span: 11,
+            max_future_block_time: 2 * 60 * 60,
+            coinbase_maturity: 10,
+            genesis_timestamp: Utc.with_ymd_and_hms(2025, 1, 1, 0, 0, 0).unwrap(),
+            genesis_nonce: 3104,
+        }
+    }
+
+    /// mainnet's rules on a chain of its own
+    pub fn testnet() -> Self {
+        ChainParams {
+            network: Network::Testnet,
+            magic: *b"BTCT",
+            default_port: 19000,
+            default_rpc_port: 19332,
+            genesis_timestamp: Utc.with_ymd_and_hms(2025, 1, 2, 0, 0, 0).unwrap(),
+            genesis_nonce: 7441,
+            ..Self::mainnet()
+        }
+    }
+
+    /// nearly any hash meets the target and it never changes
+    pub fn regtest() -> Self {
+        ChainParams {
+            network: Network::Regtest,
+            magic: *b"BTCR",
+            default_port: 19444,
+            default_rpc_port: 19443,
+            halving_interval: 150,
+            minimum_target: REGTEST_MINIMUM_TARGET.to_target(),
+            retargeting: false,
+            genesis_timestamp: Utc.with_ymd_and_hms(2025, 1, 3, 0, 0, 0).unwrap(),
+            genesis_nonce: 0,
+            ..Self::mainnet()
+        }
+    }
+
+    pub fn for_network(network: Network) -> Self {
+        match network {
+            Network::Mainnet => Self::mainnet(),
+            Network::Testnet => Self::testnet(),
+            Network::Regtest => Self::regtest(),
+        }
+    }
+
+    /// subsidy in satoshis for a block at the given height
+    pub fn block_reward(&self, block_height: u64) -> u64 {
+        let halvings = block_height / self.halving_interval;
+        if halvings >= 64 {
+            return 0;
+        }
+        (self.initial_reward * 10u64.pow(8)) >> halvings
+    }
+
+    /// median of the first `median_time_span` timestamps, newest first.
+    /// timestamps of single blocks may be off, the median hardly moves
+    pub fn median_time_past(
+        &self,
+        timestamps: impl IntoIterator<Item = DateTime<Utc>>,
+    ) -> DateTime<Utc> {
+        let mut timestamps: Vec<_> = timestamps.into_iter().take(self.median_time_span).collect();
+        timestamps.sort();
+        timestamps
+            .get(timestamps.len() / 2)
+            .copied()
+            .unwrap_or(DateTime::UNIX_EPOCH)
+    }
+
+    /// target of the block at `height`, whose parent has `parent_bits`.
+    /// `timestamps` are those of the parent and the blocks before it,
+    /// newest first. every `difficulty_update_interval` blocks the target
+    /// is scaled by how long the last interval took compared to
+    /// `ideal_block_time`, by at most a factor of 4 either way
+    pub fn next_target(
+        &self,
+        height: u64,
+        parent_bits: CompactTarget,
+        timestamps: impl IntoIterator<Item = DateTime<Utc>>,
+    ) -> CompactTarget {
+        let interval = self.difficulty_update_interval;
+        if !self.retargeting || height == 0 || !height.is_multiple_of(interval) {
+            return parent_bits;
+        }
+        // the parent back to the last block of the previous interval, the
+        // first interval has no such block and is one block shorter
+        let timestamps: Vec<_> = timestamps.into_iter().take(interval as usize + 1).collect();
+        let (Some(last), Some(first)) = (timestamps.first(), timestamps.last()) else {
+            return parent_bits;
+        };
+        let ideal_seconds = self.ideal_block_time * (timestamps.len() as u64 - 1);
+        if ideal_seconds == 0 {
+            return parent_bits;
+        }
+        // timestamps may go backwards a little, clamping covers that too
+        let actual_seconds = (*last - *first).num_seconds().clamp(
+            (ideal_seconds / 4).max(1) as i64,
+            (ideal_seconds * 4) as i64,
+        ) as u64;
+        // an overflow can only mean a target easier than the minimum one
+        let target = parent_bits
+            .to_target()
+            .checked_mul(U256::from(actual_seconds))
+            .map(|target| target / U256::from(ideal_seconds))
+            .unwrap_or(self.minimum_target)
+            .min(self.minimum_target);
+        CompactTarget::from_target(target)
+    }
+
+    /// how many times harder than the minimum target `bits` is to meet
+    pub fn difficulty(&self, bits: CompactTarget) -> f64 {
+        to_f64(self.minimum_target) / to_f64(bits.to_target())
+    }
+
+    /// the first block of the network, every chain starts with it
+    pub fn genesis_block(&self) -> Block {
+        let coinbase = Transaction::new(
+            vec![],
+            vec![TransactionOutput {
+                value: self.block_reward(0),
+                unique_id: Uuid::nil(),
+                script_pubkey: Script(vec![
+                    Op::Return,
+                    Op::Push(GENESIS_MESSAGE.as_bytes().to_vec()),
+                ]),
+            }],
+        );
+        let transactions = vec![coinbase];
+        let header = BlockHeader::new(
+            self.genesis_timestamp,
+            self.genesis_nonce,
+            Hash::zero(),
+            MerkleRoot::calculate(&transactions),
+            CompactTarget::from_target(self.minimum_target),
+        );
+        Block::new(header, transactions)
+    }
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        Self::mainnet()
+    }
+}
+
+// close enough for display purposes
+fn to_f64(value: U256) -> f64 {
+    value
+        .0
+        .iter()
+        .rev()
+        .fold(0.0, |sum, word| sum * 2f64.powi(64) + *word as f64)
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Network::Mainnet => write!(f, "mainnet"),
+            Network::Testnet => write!(f, "testnet"),
+            Network::Regtest => write!(f, "regtest"),
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = BtcError;
+
+    fn from_str(s: &str) -> Result<Self, BtcError> {
+        match s {
+            "mainnet" => Ok(Network::Mainnet),
+            "testnet" => Ok(Network::Testnet),
+            "regtest" => Ok(Network::Regtest),
+            _ => Err(BtcError::InvalidNetwork),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeDelta;
+
+    // newest first, `spacing` seconds apart
+    fn timestamps(count: u64, spacing: i64) -> Vec<DateTime<Utc>> {
+        let newest = Utc.with_ymd_and_hms(2025, 6, 1, 0, 0, 0).unwrap();
+        (0..count as i64)
+            .map(|i| newest - TimeDelta::seconds(i * spacing))
+            .collect()
+    }
+
+    fn scaled(bits: CompactTarget, numerator: u64, denominator: u64) -> CompactTarget {
+        CompactTarget::from_target(
+            bits.to_target() * U256::from(numerator) / U256::from(denominator),
+        )
+    }
+
+    fn parent_bits(params: &ChainParams) -> CompactTarget {
+        CompactTarget::from_target(params.minimum_target / U256::from(1000))
+    }
+
+    #[test]
+    fn target_only_changes_at_interval_boundaries() {
+        let params = ChainParams::mainnet();
+        let bits = parent_bits(&params);
+        let slow = timestamps(params.difficulty_update_interval + 1, 100);
+        assert_eq!(params.next_target(99, bits, slow.clone()), bits);
+        assert_ne!(params.next_target(100, bits, slow.clone()), bits);
+        let regtest = ChainParams::regtest();
+        assert_eq!(regtest.next_target(100, bits, slow), bits);
+    }
+
+    #[test]
+    fn retarget_scales_with_the_time_taken() {
+        let params = ChainParams::mainnet();
+        let interval = params.difficulty_update_interval;
+        let ideal = params.ideal_block_time as i64;
+        let bits = parent_bits(&params);
+        // twice as slow as intended, the target doubles
+        let slow = timestamps(interval + 1, 2 * ideal);
+        assert_eq!(
+            params.next_target(2 * interval, bits, slow),
+            scaled(bits, 2, 1)
+        );
+        // twice as fast, it halves
+        let fast = timestamps(interval + 1, ideal / 2);
+        assert_eq!(
+            params.next_target(2 * interval, bits, fast),
+            scaled(bits, 1, 2)
+        );
+    }
+
+    #[test]
+    fn first_interval_is_one_block_shorter() {
+        let params = ChainParams::mainnet();
+        let interval = params.difficulty_update_interval;
+        let ideal = params.ideal_block_time as i64;
+        let bits = parent_bits(&params);
+        // the genesis block and the blocks after it, no block before
+        let slow = timestamps(interval, 2 * ideal);
+        assert_eq!(params.next_target(interval, bits, slow), scaled(bits, 2, 1));
+    }
+
+    #[test]
+    fn retarget_is_clamped_to_a_factor_of_four() {
+        let params = ChainParams::mainnet();
+        let interval = params.difficulty_update_interval;
+        let ideal = params.ideal_block_time as i64;
+        let bits = parent_bits(&params);
+        let very_slow = timestamps(interval + 1, 100 * ideal);
+        assert_eq!(
+            params.next_target(interval * 2, bits, very_slow),
+            scaled(bits, 4, 1)
+        );
+        let instant = timestamps(interval + 1, 0);
+        assert_eq!(
+            params.next_target(interval * 2, bits, instant),
+            scaled(bits, 1, 4)
+        );
+    }
+
+    #[test]
+    fn retarget_never_goes_past_the_minimum_target() {
+        let params = ChainParams::mainnet();
+        let interval = params.difficulty_update_interval;
+        let minimum = CompactTarget::from_target(params.minimum_target);
+        let slow = timestamps(interval + 1, 4 * params.ideal_block_time as i64);
+        assert_eq!(
+            params.next_target(interval * 2, minimum, slow.clone()),
+            minimum
+        );
+        let almost_minimum = scaled(minimum, 1, 2);
+        assert_eq!(
+            params.next_target(interval * 2, almost_minimum, slow),
+            minimum
+        );
+    }
+
+    #[test]
+    fn timestamps_going_backwards_count_as_fastest() {
+        let params = ChainParams::mainnet();
+        let interval = params.difficulty_update_interval;
+        let bits = parent_bits(&params);
+        let backwards = timestamps(interval + 1, -(params.ideal_block_time as i64));
+        assert_eq!(
+            params.next_target(interval * 2, bits, backwards),
+            scaled(bits, 1, 4)
+        );
+    }
+}
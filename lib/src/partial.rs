@@ -0,0 +1,343 @@
+use crate::crypto::{PrivateKey, PublicKey};
+use crate::error::{BtcError, Result};
+use crate::script::{Script, ScriptContext, check_signature};
+use crate::types::{SigHashType, Transaction, TransactionOutput};
+use serde::{Deserialize, Serialize};
+
+/// A transaction whose inputs are signed one key at a time, for outputs
+/// that need signatures from several parties. It travels between the
+/// signers until enough signatures are collected to finalize it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PartialTransaction {
+    /// The transaction, unlocking scripts left empty until finalized
+    pub transaction: Transaction,
+    /// Output spent by each input, signatures commit to it
+    pub spent_outputs: Vec<TransactionOutput>,
+    pub sighash_type: SigHashType,
+    /// Signatures collected so far for each input
+    pub signatures: Vec<Vec<(PublicKey, Vec<u8>)>>,
+}
+
+impl PartialTransaction {
+    pub fn new(
+        transaction: Transaction,
+        spent_outputs: Vec<TransactionOutput>,
+        sighash_type: SigHashType,
+    ) -> Result<Self> {
+        if transaction.inputs.len() != spent_outputs.len() {
+            return Err(BtcError::InvalidTransaction);
+        }
+        let signatures = vec![vec![]; spent_outputs.len()];
+        Ok(PartialTransaction {
+            transaction,
+            spent_outputs,
+            sighash_type,
+            signatures,
+        })
+    }
+
+    /// sign every input `key` can sign and has not yet, returns how many
+    pub fn sign(&mut self, key: &PrivateKey) -> Result<usize> {
+        let public_key = key.public_key();
+        let mut signed = 0;
+        for (index, spent_output) in self.spent_outputs.iter().enumerate() {
+            if !can_sign(&spent_output.script_pubkey, &public_key)
+                || self.signatures[index]
+                    .iter()
+                    .any(|(signer, _)| *signer == public_key)
+            {
+                continue;
+            }
+            let signature = self.transaction.sign_input(
+                index,
+                spent_output,
+                self.sighash_type,
+                &mut key.clone(),
+            )?;
+            self.signatures[index].push((public_key.clone(), signature));
+            signed += 1;
+        }
+        Ok(signed)
+    }
+
+    /// add the signatures another signer collected for the same transaction.
+    /// every one of them has to be a valid signature of a key the spent
+    /// output asks for, otherwise nothing is added
+    pub fn combine(&mut self, other: PartialTransaction) -> Result<()> {
+        if other.transaction.hash() != self.transaction.hash()
+            || other.sighash_type != self.sighash_type
+            || other.signatures.len() != self.signatures.len()
+            || other.spent_outputs.len() != self.spent_outputs.len()
+            || self
+                .spent_outputs
+                .iter()
+                .zip(&other.spent_outputs)
+                .any(|(ours, theirs)| ours.hash() != theirs.hash())
+        {
+            return Err(BtcError::InvalidTransaction);
+        }
+        for (index, others) in other.signatures.iter().enumerate() {
+            for (public_key, signature) in others {
+                if !self.is_valid_signature(index, public_key, signature) {
+                    return Err(BtcError::InvalidSignature);
+                }
+            }
+        }
+        for (signatures, others) in self.signatures.iter_mut().zip(other.signatures) {
+            for (public_key, signature) in others {
+                if !signatures.iter().any(|(signer, _)| *signer == public_key) {
+                    signatures.push((public_key, signature));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.spent_outputs
+            .iter()
+            .zip(&self.signatures)
+            .all(|(output, signatures)| {
+                unlocking_script(&output.script_pubkey, signatures).is_some()
+            })
+    }
+
+    // a signature by `public_key` of input `index` with our sighash type
+    fn is_valid_signature(&self, index: usize, public_key: &PublicKey, signature: &[u8]) -> bool {
+        let spent_output = &self.spent_outputs[index];
+        let context = ScriptContext {
+            transaction: &self.transaction,
+            input_index: index,
+            spent_output,
+        };
+        can_sign(&spent_output.script_pubkey, public_key)
+            && signature.last() == Some(&self.sighash_type.to_byte())
+            && check_signature(signature, &public_key.to_bytes(), &context)
+    }
+
+    /// put the signatures into unlocking scripts, fails while any input
+    /// is still missing some
+    pub fn finalize(mut self) -> Result<Transaction> {
+        for (index, output) in self.spent_outputs.iter().enumerate() {
+            self.transaction.inputs[index].script_sig =
+                unlocking_script(&output.script_pubkey, &self.signatures[index])
+                    .ok_or(BtcError::InvalidSignature)?;
+        }
+        Ok(self.transaction)
+    }
+}
+
+/// whether `public_key` is one of the keys `script_pubkey` asks a signature of
+pub fn can_sign(script_pubkey: &Script, public_key: &PublicKey) -> bool {
+    *script_pubkey == Script::p2pk(public_key)
+        || *script_pubkey == Script::p2pkh(public_key)
+        || script_pubkey
+            .multisig_keys()
+            .is_some_and(|(_, public_keys)| public_keys.contains(public_key))
+}
+
+/// the script unlocking `script_pubkey` with the given signatures, `None`
+/// if there are not enough of them or the script is not key based
+pub fn unlocking_script(
+    script_pubkey: &Script,
+    signatures: &[(PublicKey, Vec<u8>)],
+) -> Option<Script> {
+    if let Some((required, public_keys)) = script_pubkey.multisig_keys() {
+        // checkmultisig wants the signatures in the order of the keys
+        let ordered: Vec<Vec<u8>> = public_keys
+            .iter()
+            .filter_map(|public_key| {
+                signatures
+                    .iter()
+                    .find(|(signer, _)| signer == public_key)
+                    .map(|(_, signature)| signature.clone())
+            })
+            .take(required)
+            .collect();
+        return (ordered.len() == required).then(|| Script::unlock_with_signatures(ordered));
+    }
+    signatures.iter().find_map(|(public_key, signature)| {
+        if *script_pubkey == Script::p2pk(public_key) {
+            Some(Script::unlock_with_signatures(vec![signature.clone()]))
+        } else if *script_pubkey == Script::p2pkh(public_key) {
+            Some(Script::unlock_p2pkh(signature.clone(), public_key))
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script::verify_script;
+    use crate::sha256::Hash;
+    use crate::types::{OutPoint, Sequence, TransactionInput};
+    use uuid::Uuid;
+
+    fn output(script_pubkey: Script, value: u64) -> TransactionOutput {
+        TransactionOutput {
+            value,
+            unique_id: Uuid::new_v4(),
+            script_pubkey,
+        }
+    }
+
+    // spends a single output locked with `script_pubkey`
+    fn partial(script_pubkey: Script) -> PartialTransaction {
+        let input = TransactionInput {
+            previous_output: OutPoint::new(Hash::hash(&Uuid::new_v4()), 0),
+            script_sig: Script::default(),
+            sequence: Sequence::Final,
+        };
+        let recipient = Script::p2pkh(&PrivateKey::new_key().public_key());
+        let transaction = Transaction::new(vec![input], vec![output(recipient, 90)]);
+        PartialTransaction::new(
+            transaction,
+            vec![output(script_pubkey, 100)],
+            SigHashType::ALL,
+        )
+        .unwrap()
+    }
+
+    fn keys(count: usize) -> Vec<PrivateKey> {
+        (0..count).map(|_| PrivateKey::new_key()).collect()
+    }
+
+    fn two_of_three(keys: &[PrivateKey]) -> Script {
+        let public_keys: Vec<PublicKey> = keys.iter().map(PrivateKey::public_key).collect();
+        Script::multisig(2, &public_keys)
+    }
+
+    fn verifies(transaction: &Transaction, spent_output: &TransactionOutput) -> bool {
+        let context = ScriptContext {
+            transaction,
+            input_index: 0,
+            spent_output,
+        };
+        verify_script(
+            &transaction.inputs[0].script_sig,
+            &spent_output.script_pubkey,
+            &context,
+        )
+        .is_ok()
+    }
+
+    #[test]
+    fn spent_outputs_have_to_match_the_inputs() {
+        let mut partial = partial(Script::default());
+        partial.spent_outputs.clear();
+        let result = PartialTransaction::new(partial.transaction, vec![], SigHashType::ALL);
+        assert!(matches!(result, Err(BtcError::InvalidTransaction)));
+    }
+
+    #[test]
+    fn key_signs_only_what_it_can_and_only_once() {
+        let key = PrivateKey::new_key();
+        let mut partial = partial(Script::p2pkh(&key.public_key()));
+        assert_eq!(partial.sign(&PrivateKey::new_key()).unwrap(), 0);
+        assert!(!partial.is_complete());
+        assert_eq!(partial.sign(&key).unwrap(), 1);
+        assert_eq!(partial.sign(&key).unwrap(), 0);
+        assert_eq!(partial.signatures[0].len(), 1);
+        assert!(partial.is_complete());
+        let spent_output = partial.spent_outputs[0].clone();
+        assert!(verifies(&partial.finalize().unwrap(), &spent_output));
+    }
+
+    #[test]
+    fn two_of_three_signers_combine() {
+        let keys = keys(3);
+        let unsigned = partial(two_of_three(&keys));
+        let mut first = unsigned.clone();
+        first.sign(&keys[0]).unwrap();
+        assert!(!first.is_complete());
+        assert!(matches!(
+            first.clone().finalize(),
+            Err(BtcError::InvalidSignature)
+        ));
+
+        let mut third = unsigned.clone();
+        third.sign(&keys[2]).unwrap();
+        first.combine(third.clone()).unwrap();
+        assert!(first.is_complete());
+        // combining again adds nothing
+        first.combine(third).unwrap();
+        assert_eq!(first.signatures[0].len(), 2);
+
+        let spent_output = first.spent_outputs[0].clone();
+        assert!(verifies(&first.finalize().unwrap(), &spent_output));
+    }
+
+    #[test]
+    fn combine_rejects_a_different_transaction() {
+        let keys = keys(3);
+        let mut ours = partial(two_of_three(&keys));
+        let mut theirs = ours.clone();
+        theirs.transaction.outputs[0].value += 1;
+        theirs.sign(&keys[1]).unwrap();
+        assert!(matches!(
+            ours.combine(theirs),
+            Err(BtcError::InvalidTransaction)
+        ));
+
+        let mut theirs = ours.clone();
+        theirs.spent_outputs[0].value += 1;
+        theirs.sign(&keys[1]).unwrap();
+        assert!(matches!(
+            ours.combine(theirs),
+            Err(BtcError::InvalidTransaction)
+        ));
+        assert!(ours.signatures[0].is_empty());
+    }
+
+    #[test]
+    fn combine_rejects_invalid_signatures() {
+        let keys = keys(3);
+        let mut ours = partial(two_of_three(&keys));
+        ours.sign(&keys[0]).unwrap();
+
+        // a signature that does not verify, claimed by a listed key
+        let mut forged = ours.clone();
+        let (_, signature) = forged.signatures[0][0].clone();
+        forged.signatures[0] = vec![(keys[1].public_key(), signature)];
+        assert!(matches!(
+            ours.combine(forged),
+            Err(BtcError::InvalidSignature)
+        ));
+
+        // a valid signature by a key the script does not ask for
+        let stranger = PrivateKey::new_key();
+        let mut theirs = ours.clone();
+        let signature = theirs
+            .transaction
+            .sign_input(
+                0,
+                &theirs.spent_outputs[0],
+                SigHashType::ALL,
+                &mut stranger.clone(),
+            )
+            .unwrap();
+        theirs.signatures[0].push((stranger.public_key(), signature));
+        assert!(matches!(
+            ours.combine(theirs),
+            Err(BtcError::InvalidSignature)
+        ));
+
+        // a good signature with another sighash type than agreed on
+        let mut theirs = ours.clone();
+        let none = SigHashType::from_byte(0x02).unwrap();
+        let signature = theirs
+            .transaction
+            .sign_input(0, &theirs.spent_outputs[0], none, &mut keys[1].clone())
+            .unwrap();
+        theirs.signatures[0].push((keys[1].public_key(), signature));
+        assert!(matches!(
+            ours.combine(theirs),
+            Err(BtcError::InvalidSignature)
+        ));
+
+        assert_eq!(ours.signatures[0].len(), 1);
+        assert!(!ours.is_complete());
+    }
+}
@@ -0,0 +1,122 @@
+use serde_json::{Value, json};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+
+/// Minimal http client for the node's json-rpc interface, used by the
+/// miner and the wallet
+pub struct RpcClient {
+    addr: String,
+}
+
+impl RpcClient {
+    pub fn new(addr: impl Into<String>) -> Self {
+        RpcClient { addr: addr.into() }
+    }
+
+    pub fn call(&self, method: &str, params: Value) -> io::Result<Value> {
+        let body = json!({ "id": 1, "method": method, "params": params }).to_string();
+        let mut stream = TcpStream::connect(&self.addr)?;
+        write!(
+            stream,
+            "POST / HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.addr,
+            body.len(),
+            body
+        )?;
+        stream.flush()?;
+
+        let mut reader = BufReader::new(stream);
+        let mut status_line = String::new();
+        reader.read_line(&mut status_line)?;
+        if status_line.split_whitespace().nth(1) != Some("200") {
+            return Err(io::Error::other(format!(
+                "rpc request failed: {}",
+                status_line.trim_end()
+            )));
+        }
+        // the node closes the connection after replying, so
+        // everything after the headers is the body
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+                break;
+            }
+        }
+        let mut body = vec![];
+        reader.read_to_end(&mut body)?;
+        let mut response: Value = serde_json::from_slice(&body)?;
+        if let Some(error) = response.get("error").filter(|error| !error.is_null()) {
+            return Err(io::Error::other(format!(
+                "{} (code {})",
+                error["message"].as_str().unwrap_or("unknown error"),
+                error["code"]
+            )));
+        }
+        Ok(response["result"].take())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    // answers a single request with `response` and hands back the request
+    fn serve_once(response: &'static str) -> (RpcClient, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request = String::new();
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if let Some(length) = line.strip_prefix("Content-Length: ") {
+                    content_length = length.trim().parse().unwrap();
+                }
+                if line.trim_end().is_empty() {
+                    break;
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            request.push_str(&String::from_utf8(body).unwrap());
+            stream.write_all(response.as_bytes()).unwrap();
+            request
+        });
+        (RpcClient::new(addr.to_string()), handle)
+    }
+
+    #[test]
+    fn result_is_returned() {
+        let (client, server) = serve_once(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n{\"id\":1,\"result\":[1,2],\"error\":null}",
+        );
+        let result = client.call("getblockcount", json!([])).unwrap();
+        assert_eq!(result, json!([1, 2]));
+        let request: Value = serde_json::from_str(&server.join().unwrap()).unwrap();
+        assert_eq!(request["method"], "getblockcount");
+        assert_eq!(request["params"], json!([]));
+    }
+
+    #[test]
+    fn error_object_becomes_an_error() {
+        let (client, server) = serve_once(
+            "HTTP/1.1 200 OK\r\n\r\n{\"id\":1,\"result\":null,\"error\":{\"code\":-32601,\"message\":\"Method not found\"}}",
+        );
+        let error = client.call("nope", json!([])).unwrap_err();
+        assert!(error.to_string().contains("Method not found"));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn http_failure_becomes_an_error() {
+        let (client, server) = serve_once("HTTP/1.1 413 Payload Too Large\r\n\r\n");
+        let error = client.call("getblockcount", json!([])).unwrap_err();
+        assert!(error.to_string().contains("413"));
+        server.join().unwrap();
+    }
+}
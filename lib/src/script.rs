@@ -0,0 +1,519 @@
+use crate::crypto::{PublicKey, Signature};
+use crate::error::{BtcError, Result};
+use crate::types::{LockTime, Sequence, SigHashType, Transaction, TransactionOutput};
+use serde::{Deserialize, Serialize};
+use sha256::digest;
+use std::fmt;
+
+// limits that keep a hostile script from eating the node
+const MAX_SCRIPT_OPS: usize = 201;
+const MAX_STACK_SIZE: usize = 1000;
+const MAX_ITEM_SIZE: usize = 520;
+pub const MAX_MULTISIG_KEYS: usize = 20;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum Op {
+    /// Push raw bytes
+    Push(Vec<u8>),
+    /// Push a number as 8 little endian bytes
+    Number(i64),
+    Dup,
+    Drop,
+    Swap,
+    If,
+    NotIf,
+    Else,
+    EndIf,
+    /// Fail unless the top item is true, popping it
+    Verify,
+    /// Fail right away, marks an output as unspendable
+    Return,
+    Equal,
+    EqualVerify,
+    Sha256,
+    /// Pop a public key and a signature, push whether the signature
+    /// is valid for the spending transaction
+    CheckSig,
+    CheckSigVerify,
+    /// Pop n, n public keys, m and m signatures. signatures have to be
+    /// in the same order as their keys
+    CheckMultiSig,
+    CheckMultiSigVerify,
+    /// Fail unless the spending transaction's lock time is at least the
+    /// top item, which is left on the stack
+    CheckLockTimeVerify,
+    /// Fail unless the spending input's sequence is at least the top
+    /// item, which is left on the stack
+    CheckSequenceVerify,
+}
+
+/// A list of operations. Outputs are locked with a script and inputs
+/// run theirs first to leave what unlocks it on the stack
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+pub struct Script(pub Vec<Op>);
+
+/// What a script is checked against
+pub struct ScriptContext<'a> {
+    pub transaction: &'a Transaction,
+    pub input_index: usize,
+    pub spent_output: &'a TransactionOutput,
+}
+
+impl Script {
+    /// pay to a public key
+    pub fn p2pk(public_key: &PublicKey) -> Self {
+        Script(vec![Op::Push(public_key.to_bytes()), Op::CheckSig])
+    }
+
+    /// pay to the hash of a public key, which is revealed when spending
+    pub fn p2pkh(public_key: &PublicKey) -> Self {
+        Script(vec![
+            Op::Dup,
+            Op::Sha256,
+            Op::Push(sha256(&public_key.to_bytes())),
+            Op::EqualVerify,
+            Op::CheckSig,
+        ])
+    }
+
+    /// `required` signatures out of `public_keys`. panics for more than
+    /// `MAX_MULTISIG_KEYS` keys or more signatures than keys, such a
+    /// script could never be spent
+    pub fn multisig(required: usize, public_keys: &[PublicKey]) -> Self {
+        assert!(
+            public_keys.len() <= MAX_MULTISIG_KEYS,
+            "multisig with more than {} keys",
+            MAX_MULTISIG_KEYS
+        );
+        assert!(
+            required <= public_keys.len(),
+            "multisig requiring more signatures than keys"
+        );
+        let mut ops = vec![Op::Number(required as i64)];
+        ops.extend(public_keys.iter().map(|key| Op::Push(key.to_bytes())));
+        ops.push(Op::Number(public_keys.len() as i64));
+        ops.push(Op::CheckMultiSig);
+        Script(ops)
+    }
+
+    /// spendable by `public_key` once the sha256 preimage of `hash` is known
+    pub fn hashlock(hash: [u8; 32], public_key: &PublicKey) -> Self {
+        Script(vec![
+            Op::Sha256,
+            Op::Push(hash.to_vec()),
+            Op::EqualVerify,
+            Op::Push(public_key.to_bytes()),
+            Op::CheckSig,
+        ])
+    }
+
+    /// spendable by `public_key` once `lock_time` has passed, the spending
+    /// transaction has to carry a lock time at least as late and enforce it
+    pub fn timelock(lock_time: LockTime, public_key: &PublicKey) -> Self {
+        Script(vec![
+            Op::Number(lock_time.to_number()),
+            Op::CheckLockTimeVerify,
+            Op::Drop,
+            Op::Push(public_key.to_bytes()),
+            Op::CheckSig,
+        ])
+    }
+
+    /// spendable by `public_key` once `sequence` passed since the output
+    /// confirmed, the spending input has to carry a sequence at least as long
+    pub fn relative_timelock(sequence: Sequence, public_key: &PublicKey) -> Self {
+        Script(vec![
+            Op::Number(sequence.to_number()),
+            Op::CheckSequenceVerify,
+            Op::Drop,
+            Op::Push(public_key.to_bytes()),
+            Op::CheckSig,
+        ])
+    }
+
+    /// unlocks p2pk, timelock and, given in key order, multisig outputs
+    pub fn unlock_with_signatures(signatures: Vec<Vec<u8>>) -> Self {
+        Script(signatures.into_iter().map(Op::Push).collect())
+    }
+
+    pub fn unlock_p2pkh(signature: Vec<u8>, public_key: &PublicKey) -> Self {
+        Script(vec![Op::Push(signature), Op::Push(public_key.to_bytes())])
+    }
+
+    pub fn unlock_hashlock(signature: Vec<u8>, preimage: Vec<u8>) -> Self {
+        Script(vec![Op::Push(signature), Op::Push(preimage)])
+    }
+
+    /// the required count and keys of a script made by `multisig`
+    pub fn multisig_keys(&self) -> Option<(usize, Vec<PublicKey>)> {
+        let [
+            Op::Number(required),
+            keys @ ..,
+            Op::Number(count),
+            Op::CheckMultiSig,
+        ] = self.0.as_slice()
+        else {
+            return None;
+        };
+        let public_keys = keys
+            .iter()
+            .map(|op| match op {
+                Op::Push(bytes) => PublicKey::from_bytes(bytes),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+        let required = usize::try_from(*required).ok()?;
+        if *count != public_keys.len() as i64 || required > public_keys.len() {
+            return None;
+        }
+        Some((required, public_keys))
+    }
+
+    fn is_push_only(&self) -> bool {
+        self.0
+            .iter()
+            .all(|op| matches!(op, Op::Push(_) | Op::Number(_)))
+    }
+}
+
+/// run the unlocking script of an input and then the locking script of
+/// the output it spends, the input is valid if that leaves a single true
+pub fn verify_script(
+    script_sig: &Script,
+    script_pubkey: &Script,
+    context: &ScriptContext,
+) -> Result<()> {
+    // anything but data in the unlocking script would let a third
+    // party change the transaction without invalidating signatures
+    if !script_sig.is_push_only() {
+        return Err(BtcError::InvalidScript);
+    }
+    let mut stack = vec![];
+    execute(script_sig, &mut stack, context)?;
+    execute(script_pubkey, &mut stack, context)?;
+    // leftover items would let anyone push extra data in front of the
+    // unlocking script, changing the txid without touching a signature
+    match stack.as_slice() {
+        [top] if is_true(top) => Ok(()),
+        _ => Err(BtcError::InvalidScript),
+    }
+}
+
+fn execute(script: &Script, stack: &mut Vec<Vec<u8>>, context: &ScriptContext) -> Result<()> {
+    if script.0.len() > MAX_SCRIPT_OPS {
+        return Err(BtcError::InvalidScript);
+    }
+    // one entry per open if, whether its branch runs
+    let mut branches: Vec<bool> = vec![];
+    for op in &script.0 {
+        let executing = branches.iter().all(|branch| *branch);
+        match op {
+            Op::If | Op::NotIf => {
+                let mut taken = false;
+                if executing {
+                    taken = is_true(&pop(stack)?);
+                    if *op == Op::NotIf {
+                        taken = !taken;
+                    }
+                }
+                branches.push(taken);
+                continue;
+            }
+            Op::Else => {
+                let branch = branches.last_mut().ok_or(BtcError::InvalidScript)?;
+                *branch = !*branch;
+                continue;
+            }
+            Op::EndIf => {
+                branches.pop().ok_or(BtcError::InvalidScript)?;
+                continue;
+            }
+            _ if !executing => continue,
+            _ => {}
+        }
+        match op {
+            Op::Push(data) => {
+                if data.len() > MAX_ITEM_SIZE {
+                    return Err(BtcError::InvalidScript);
+                }
+                stack.push(data.clone());
+            }
+            Op::Number(number) => stack.push(number.to_le_bytes().to_vec()),
+            Op::Dup => {
+                let top = stack.last().ok_or(BtcError::InvalidScript)?.clone();
+                stack.push(top);
+            }
+            Op::Drop => {
+                pop(stack)?;
+            }
+            Op::Swap => {
+                let len = stack.len();
+                if len < 2 {
+                    return Err(BtcError::InvalidScript);
+                }
+                stack.swap(len - 1, len - 2);
+            }
+            Op::Verify => verify(stack)?,
+            Op::Return => return Err(BtcError::InvalidScript),
+            Op::Equal | Op::EqualVerify => {
+                let a = pop(stack)?;
+                let b = pop(stack)?;
+                stack.push(boolean(a == b));
+                if *op == Op::EqualVerify {
+                    verify(stack)?;
+                }
+            }
+            Op::Sha256 => {
+                let data = pop(stack)?;
+                stack.push(sha256(&data));
+            }
+            Op::CheckSig | Op::CheckSigVerify => {
+                let public_key = pop(stack)?;
+                let signature = pop(stack)?;
+                stack.push(boolean(check_signature(&signature, &public_key, context)));
+                if *op == Op::CheckSigVerify {
+                    verify(stack)?;
+                }
+            }
+            Op::CheckMultiSig | Op::CheckMultiSigVerify => {
+                let valid = check_multisig(stack, context)?;
+                stack.push(boolean(valid));
+                if *op == Op::CheckMultiSigVerify {
+                    verify(stack)?;
+                }
+            }
+            // the locks themselves are checked with the transaction, the
+            // script only makes sure they are there
+            Op::CheckLockTimeVerify => {
+                let lock_time = number(stack.last().ok_or(BtcError::InvalidScript)?)?;
+                // a lock time that is not enforced would not lock anything
+                if lock_time < 0
+                    || !context.transaction.is_lock_time_enabled()
+                    || !context.transaction.lock_time.reaches(lock_time)
+                {
+                    return Err(BtcError::InvalidScript);
+                }
+            }
+            Op::CheckSequenceVerify => {
+                let sequence = number(stack.last().ok_or(BtcError::InvalidScript)?)?;
+                let input = &context.transaction.inputs[context.input_index];
+                if sequence < 0 || !input.sequence.reaches(sequence) {
+                    return Err(BtcError::InvalidScript);
+                }
+            }
+            Op::If | Op::NotIf | Op::Else | Op::EndIf => unreachable!(),
+        }
+        if stack.len() > MAX_STACK_SIZE {
+            return Err(BtcError::InvalidScript);
+        }
+    }
+    if !branches.is_empty() {
+        return Err(BtcError::InvalidScript);
+    }
+    Ok(())
+}
+
+fn check_multisig(stack: &mut Vec<Vec<u8>>, context: &ScriptContext) -> Result<bool> {
+    let key_count = count(&pop(stack)?)?;
+    let keys = pop_many(stack, key_count)?;
+    let required = count(&pop(stack)?)?;
+    if required > key_count {
+        return Err(BtcError::InvalidScript);
+    }
+    let signatures = pop_many(stack, required)?;
+    // every signature has to match a key after the one before it
+    let mut keys = keys.iter();
+    for signature in &signatures {
+        if !keys
+            .by_ref()
+            .any(|key| check_signature(signature, key, context))
+        {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+// a signature is the ecdsa signature followed by the sighash type byte
+pub(crate) fn check_signature(
+    signature: &[u8],
+    public_key: &[u8],
+    context: &ScriptContext,
+) -> bool {
+    let Some((type_byte, signature)) = signature.split_last() else {
+        return false;
+    };
+    let (Some(sighash_type), Some(signature), Some(public_key)) = (
+        SigHashType::from_byte(*type_byte),
+        Signature::from_bytes(signature),
+        PublicKey::from_bytes(public_key),
+    ) else {
+        return false;
+    };
+    context
+        .transaction
+        .signature_hash(context.input_index, context.spent_output, sighash_type)
+        .is_ok_and(|sighash| signature.verify(&sighash, &public_key))
+}
+
+fn pop(stack: &mut Vec<Vec<u8>>) -> Result<Vec<u8>> {
+    stack.pop().ok_or(BtcError::InvalidScript)
+}
+
+// pops `count` items, returned in the order they were pushed
+fn pop_many(stack: &mut Vec<Vec<u8>>, count: usize) -> Result<Vec<Vec<u8>>> {
+    if stack.len() < count {
+        return Err(BtcError::InvalidScript);
+    }
+    Ok(stack.split_off(stack.len() - count))
+}
+
+fn verify(stack: &mut Vec<Vec<u8>>) -> Result<()> {
+    if is_true(&pop(stack)?) {
+        Ok(())
+    } else {
+        Err(BtcError::InvalidScript)
+    }
+}
+
+fn number(item: &[u8]) -> Result<i64> {
+    let bytes: [u8; 8] = item.try_into().map_err(|_| BtcError::InvalidScript)?;
+    Ok(i64::from_le_bytes(bytes))
+}
+
+// a key or signature count for multisig
+fn count(item: &[u8]) -> Result<usize> {
+    let count = number(item)?;
+    if !(0..=MAX_MULTISIG_KEYS as i64).contains(&count) {
+        return Err(BtcError::InvalidScript);
+    }
+    Ok(count as usize)
+}
+
+fn boolean(value: bool) -> Vec<u8> {
+    if value { vec![1] } else { vec![] }
+}
+
+fn is_true(item: &[u8]) -> bool {
+    item.iter().any(|byte| *byte != 0)
+}
+
+pub fn sha256(data: &[u8]) -> Vec<u8> {
+    hex::decode(digest(data)).unwrap()
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Op::Push(data) => write!(f, "{}", hex::encode(data)),
+            Op::Number(number) => write!(f, "{}", number),
+            Op::Dup => write!(f, "OP_DUP"),
+            Op::Drop => write!(f, "OP_DROP"),
+            Op::Swap => write!(f, "OP_SWAP"),
+            Op::If => write!(f, "OP_IF"),
+            Op::NotIf => write!(f, "OP_NOTIF"),
+            Op::Else => write!(f, "OP_ELSE"),
+            Op::EndIf => write!(f, "OP_ENDIF"),
+            Op::Verify => write!(f, "OP_VERIFY"),
+            Op::Return => write!(f, "OP_RETURN"),
+            Op::Equal => write!(f, "OP_EQUAL"),
+            Op::EqualVerify => write!(f, "OP_EQUALVERIFY"),
+            Op::Sha256 => write!(f, "OP_SHA256"),
+            Op::CheckSig => write!(f, "OP_CHECKSIG"),
+            Op::CheckSigVerify => write!(f, "OP_CHECKSIGVERIFY"),
+            Op::CheckMultiSig => write!(f, "OP_CHECKMULTISIG"),
+            Op::CheckMultiSigVerify => write!(f, "OP_CHECKMULTISIGVERIFY"),
+            Op::CheckLockTimeVerify => write!(f, "OP_CHECKLOCKTIMEVERIFY"),
+            Op::CheckSequenceVerify => write!(f, "OP_CHECKSEQUENCEVERIFY"),
+        }
+    }
+}
+
+/// space separated ops, like bitcoin's script asm
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, op) in self.0.iter().enumerate() {
+            if index > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{}", op)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::TransactionOutput;
+    use uuid::Uuid;
+
+    fn run(script_sig: Script, script_pubkey: Script) -> Result<()> {
+        let spent_output = TransactionOutput {
+            value: 1,
+            unique_id: Uuid::nil(),
+            script_pubkey: script_pubkey.clone(),
+        };
+        let transaction = Transaction::new(vec![], vec![]);
+        let context = ScriptContext {
+            transaction: &transaction,
+            input_index: 0,
+            spent_output: &spent_output,
+        };
+        verify_script(&script_sig, &script_pubkey, &context)
+    }
+
+    fn puzzle() -> Script {
+        Script(vec![Op::Sha256, Op::Push(sha256(b"secret")), Op::Equal])
+    }
+
+    #[test]
+    fn single_true_item_is_accepted() {
+        assert!(run(Script(vec![Op::Push(b"secret".to_vec())]), puzzle()).is_ok());
+    }
+
+    #[test]
+    fn false_result_is_rejected() {
+        assert!(run(Script(vec![Op::Push(b"guess".to_vec())]), puzzle()).is_err());
+    }
+
+    #[test]
+    fn extra_items_are_rejected() {
+        let script_sig = Script(vec![Op::Push(vec![1]), Op::Push(b"secret".to_vec())]);
+        assert!(run(script_sig, puzzle()).is_err());
+    }
+
+    #[test]
+    fn unlocking_script_has_to_be_push_only() {
+        let script_sig = Script(vec![Op::Push(b"secret".to_vec()), Op::Dup, Op::Drop]);
+        assert!(run(script_sig, puzzle()).is_err());
+    }
+
+    fn public_keys(count: usize) -> Vec<PublicKey> {
+        (0..count)
+            .map(|_| crate::crypto::PrivateKey::new_key().public_key())
+            .collect()
+    }
+
+    #[test]
+    fn multisig_with_the_most_keys_allowed() {
+        let public_keys = public_keys(MAX_MULTISIG_KEYS);
+        let script = Script::multisig(MAX_MULTISIG_KEYS, &public_keys);
+        assert_eq!(
+            script.multisig_keys(),
+            Some((MAX_MULTISIG_KEYS, public_keys))
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "more than 20 keys")]
+    fn multisig_with_too_many_keys_panics() {
+        Script::multisig(1, &public_keys(MAX_MULTISIG_KEYS + 1));
+    }
+
+    #[test]
+    #[should_panic(expected = "more signatures than keys")]
+    fn multisig_requiring_more_signatures_than_keys_panics() {
+        Script::multisig(3, &public_keys(2));
+    }
+}
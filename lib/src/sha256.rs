@@ -1,19 +1,19 @@
 use crate::U256;
+use crate::encode::Encodable;
+use crate::error::BtcError;
 use serde::{Deserialize, Serialize};
 use sha256::digest;
 use std::fmt::{self};
+use std::str::FromStr;
 
 #[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Hash, Eq)]
 pub struct Hash(U256);
 
 impl Hash {
-    pub fn hash<T: serde::Serialize>(data: &T) -> Self {
-        let mut serialized: Vec<u8> = vec![];
-        if let Err(e) = ciborium::into_writer(data, &mut serialized) {
-            panic!("Failed to serialize data : {:?} This should not happen ", e);
-        };
-
-        let hash = digest(&serialized);
+    #[allow(clippy::self_named_constructors)]
+    /// sha256 of the canonical encoding of `data`
+    pub fn hash<T: Encodable + ?Sized>(data: &T) -> Self {
+        let hash = digest(data.to_bytes());
         let hash_bytes = hex::decode(hash).unwrap();
         let hash_array: [u8; 32] = hash_bytes.as_slice().try_into().unwrap();
 
@@ -28,6 +28,11 @@ impl Hash {
         Hash(U256::zero())
     }
 
+    /// inverse of `as_bytes`
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Hash(U256::from_little_endian(&bytes))
+    }
+
     pub fn as_bytes(&self) -> [u8; 32] {
         let mut bytes: Vec<u8> = vec![0; 32];
         self.0.to_little_endian(&mut bytes);
@@ -40,3 +45,14 @@ impl fmt::Display for Hash {
         write!(f, "{:x}", self.0)
     }
 }
+
+/// parses the hex form produced by `Display`
+impl FromStr for Hash {
+    type Err = BtcError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        U256::from_str_radix(s, 16)
+            .map(Hash)
+            .map_err(|_| BtcError::InvalidHash)
+    }
+}
@@ -0,0 +1,435 @@
+use crate::encode::{Decodable, Encodable};
+use crate::error::{BtcError, Result};
+use crate::sha256::Hash;
+use crate::types::Block;
+use serde::{Deserialize, Serialize};
+use sha256::digest;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// a new block file is started once the current one grows past this size
+pub const MAX_BLOCK_FILE_SIZE: u64 = 128 * 1024 * 1024;
+// every record in a block file starts with these bytes, blocks are in
+// their canonical encoding
+const RECORD_MAGIC: [u8; 4] = *b"BLK1";
+// magic + payload length + payload checksum
+const RECORD_HEADER_SIZE: u64 = 4 + 4 + 32;
+const INDEX_FILE: &str = "index.cbor";
+
+/// Where a block lives inside the block files
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BlockLocation {
+    /// Number of the blkNNNNN.dat file
+    pub file: u32,
+    /// Offset of the record header inside the file
+    pub offset: u64,
+    /// Length of the serialized block
+    pub len: u32,
+}
+
+impl BlockLocation {
+    fn end(&self) -> u64 {
+        self.offset + RECORD_HEADER_SIZE + self.len as u64
+    }
+}
+
+/// Append-only block files plus an index keyed by block hash.
+///
+/// Blocks are only ever appended, so a crash while writing can at worst
+/// leave a torn record at the end of the last file. `open` detects it with
+/// the record checksum and truncates it away, earlier blocks are never touched.
+#[derive(Debug, Clone)]
+pub struct BlockStore {
+    dir: PathBuf,
+    index: HashMap<Hash, BlockLocation>,
+    current_file: u32,
+    current_size: u64,
+    pending: Vec<Block>,
+}
+
+impl BlockStore {
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let mut store = BlockStore {
+            dir,
+            index: HashMap::new(),
+            current_file: 0,
+            current_size: 0,
+            pending: vec![],
+        };
+        // a missing or unreadable index is not fatal, it can be rebuilt
+        // from the block files themselves
+        let index = store.read_index().unwrap_or_default();
+        store.recover(index)?;
+        Ok(store)
+    }
+
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.index.contains_key(hash) || self.pending.iter().any(|block| block.hash() == *hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len() + self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// queue a block to be written on the next flush
+    pub fn stage(&mut self, block: &Block) {
+        if !self.contains(&block.hash()) {
+            self.pending.push(block.clone());
+        }
+    }
+
+    /// append all staged blocks, sync them to disk and then
+    /// atomically replace the index
+    pub fn flush(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let pending = std::mem::take(&mut self.pending);
+        if let Err(e) = self.write_blocks(&pending) {
+            // keep the blocks around so a later flush can retry
+            self.pending = pending;
+            return Err(e);
+        }
+        self.write_index()
+    }
+
+    pub fn read_block(&self, hash: &Hash) -> Result<Option<Block>> {
+        if let Some(block) = self.pending.iter().find(|block| block.hash() == *hash) {
+            return Ok(Some(block.clone()));
+        }
+        match self.index.get(hash) {
+            Some(location) => self.read_at(location).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// every stored block in the order it was written, so that
+    /// parents always come before their children
+    pub fn load_blocks(&self) -> Result<Vec<Block>> {
+        let mut locations: Vec<&BlockLocation> = self.index.values().collect();
+        locations.sort();
+        let mut blocks = locations
+            .into_iter()
+            .map(|location| self.read_at(location))
+            .collect::<Result<Vec<_>>>()?;
+        blocks.extend(self.pending.iter().cloned());
+        Ok(blocks)
+    }
+
+    fn write_blocks(&mut self, blocks: &[Block]) -> Result<()> {
+        let mut file = self.open_current_file()?;
+        for block in blocks {
+            let payload = block.to_bytes();
+            let record_size = RECORD_HEADER_SIZE + payload.len() as u64;
+            if self.current_size > 0 && self.current_size + record_size > MAX_BLOCK_FILE_SIZE {
+                file.sync_all()?;
+                self.current_file += 1;
+                self.current_size = 0;
+                file = self.open_current_file()?;
+            }
+            let location = BlockLocation {
+                file: self.current_file,
+                offset: self.current_size,
+                len: payload.len() as u32,
+            };
+            file.write_all(&RECORD_MAGIC)?;
+            file.write_all(&(payload.len() as u32).to_le_bytes())?;
+            file.write_all(&checksum(&payload))?;
+            file.write_all(&payload)?;
+            self.current_size += record_size;
+            self.index.insert(block.hash(), location);
+        }
+        // blocks have to be durable before the index points at them
+        file.sync_all()?;
+        Ok(())
+    }
+
+    fn block_file_path(&self, file: u32) -> PathBuf {
+        self.dir.join(format!("blk{:05}.dat", file))
+    }
+
+    fn open_current_file(&self) -> Result<File> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.block_file_path(self.current_file))?;
+        Ok(file)
+    }
+
+    fn read_at(&self, location: &BlockLocation) -> Result<Block> {
+        let mut file = File::open(self.block_file_path(location.file))?;
+        file.seek(SeekFrom::Start(location.offset))?;
+        match read_record(&mut file)? {
+            Some(payload) => Block::from_bytes(&payload).map_err(|_| BtcError::InvalidStorage),
+            None => Err(BtcError::InvalidStorage),
+        }
+    }
+
+    fn read_index(&self) -> Result<HashMap<Hash, BlockLocation>> {
+        let file = File::open(self.dir.join(INDEX_FILE))?;
+        ciborium::from_reader(file).map_err(|_| BtcError::InvalidStorage)
+    }
+
+    fn write_index(&self) -> Result<()> {
+        let tmp_path = self.dir.join(format!("{}.tmp", INDEX_FILE));
+        let mut file = File::create(&tmp_path)?;
+        ciborium::into_writer(&self.index, &mut file).map_err(|_| BtcError::InvalidStorage)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, self.dir.join(INDEX_FILE))?;
+        Ok(())
+    }
+
+    // check the index against the block files, pick up records that were
+    // written but never indexed and cut off a torn record at the end
+    fn recover(&mut self, index: HashMap<Hash, BlockLocation>) -> Result<()> {
+        let mut file_number = 0;
+        while self.block_file_path(file_number + 1).exists() {
+            file_number += 1;
+        }
+        let mut index: HashMap<Hash, BlockLocation> = index
+            .into_iter()
+            .filter(|(_, location)| {
+                fs::metadata(self.block_file_path(location.file))
+                    .map(|metadata| metadata.len() >= location.end())
+                    .unwrap_or(false)
+            })
+            .collect();
+        // only rescan from the end of the last indexed block, or from the
+        // very beginning if the index did not survive
+        let (mut scan_file, mut scan_offset) = index
+            .values()
+            .max()
+            .map(|location| (location.file, location.end()))
+            .unwrap_or((0, 0));
+        loop {
+            let path = self.block_file_path(scan_file);
+            if !path.exists() {
+                break;
+            }
+            let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+            file.seek(SeekFrom::Start(scan_offset))?;
+            while let Some(payload) = read_record(&mut file)? {
+                let block = match Block::from_bytes(&payload) {
+                    Ok(block) => block,
+                    Err(_) => break,
+                };
+                index.insert(
+                    block.hash(),
+                    BlockLocation {
+                        file: scan_file,
+                        offset: scan_offset,
+                        len: payload.len() as u32,
+                    },
+                );
+                scan_offset += RECORD_HEADER_SIZE + payload.len() as u64;
+            }
+            if scan_file == file_number {
+                // anything past the last good record is a torn write
+                file.set_len(scan_offset)?;
+                file.sync_all()?;
+                break;
+            }
+            scan_file += 1;
+            scan_offset = 0;
+        }
+        self.current_file = file_number;
+        self.current_size = fs::metadata(self.block_file_path(file_number))
+            .map(|metadata| metadata.len())
+            .unwrap_or(0);
+        self.index = index;
+        self.write_index()
+    }
+}
+
+fn checksum(payload: &[u8]) -> [u8; 32] {
+    let hash_bytes = hex::decode(digest(payload)).unwrap();
+    hash_bytes.as_slice().try_into().unwrap()
+}
+
+// read a single record, returns None on a clean end of file as well as on
+// a torn or corrupted record
+fn read_record(file: &mut File) -> Result<Option<Vec<u8>>> {
+    let mut header = [0u8; RECORD_HEADER_SIZE as usize];
+    if read_exact_or_eof(file, &mut header)?.is_none() {
+        return Ok(None);
+    }
+    if header[0..4] != RECORD_MAGIC {
+        return Ok(None);
+    }
+    let len = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+    let mut payload = vec![0u8; len];
+    if read_exact_or_eof(file, &mut payload)?.is_none() {
+        return Ok(None);
+    }
+    if checksum(&payload) != header[8..40] {
+        return Ok(None);
+    }
+    Ok(Some(payload))
+}
+
+fn read_exact_or_eof(file: &mut File, buf: &mut [u8]) -> Result<Option<()>> {
+    match file.read_exact(buf) {
+        Ok(()) => Ok(Some(())),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::PrivateKey;
+    use crate::params::ChainParams;
+    use crate::types::BlockChain;
+    use uuid::Uuid;
+
+    // removes the directory again once a test is done with it
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            TempDir(std::env::temp_dir().join(format!("blockstore-{}", Uuid::new_v4())))
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // blocks only have to differ for the store, they are never validated
+    fn blocks(count: usize) -> Vec<Block> {
+        let blockchain = BlockChain::new(ChainParams::regtest());
+        (0..count)
+            .map(|_| blockchain.create_block_template(PrivateKey::new_key().public_key()))
+            .collect()
+    }
+
+    fn write(dir: &Path, blocks: &[Block]) {
+        let mut store = BlockStore::open(dir).unwrap();
+        for block in blocks {
+            store.stage(block);
+        }
+        store.flush().unwrap();
+    }
+
+    fn hashes(blocks: &[Block]) -> Vec<Hash> {
+        blocks.iter().map(|block| block.hash()).collect()
+    }
+
+    fn block_file(dir: &Path) -> PathBuf {
+        dir.join("blk00000.dat")
+    }
+
+    #[test]
+    fn blocks_survive_a_reopen() {
+        let dir = TempDir::new();
+        let blocks = blocks(3);
+        write(&dir.0, &blocks);
+
+        let store = BlockStore::open(&dir.0).unwrap();
+        assert_eq!(store.len(), 3);
+        assert_eq!(hashes(&store.load_blocks().unwrap()), hashes(&blocks));
+        let read = store.read_block(&blocks[1].hash()).unwrap().unwrap();
+        assert_eq!(read.hash(), blocks[1].hash());
+    }
+
+    #[test]
+    fn torn_record_at_the_end_is_cut_off() {
+        let dir = TempDir::new();
+        let blocks = blocks(3);
+        write(&dir.0, &blocks[..2]);
+        let good_len = fs::metadata(block_file(&dir.0)).unwrap().len();
+        // the header and half the payload of the next record
+        let payload = blocks[2].to_bytes();
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(block_file(&dir.0))
+            .unwrap();
+        file.write_all(&RECORD_MAGIC).unwrap();
+        file.write_all(&(payload.len() as u32).to_le_bytes())
+            .unwrap();
+        file.write_all(&checksum(&payload)).unwrap();
+        file.write_all(&payload[..payload.len() / 2]).unwrap();
+        drop(file);
+
+        let mut store = BlockStore::open(&dir.0).unwrap();
+        assert_eq!(hashes(&store.load_blocks().unwrap()), hashes(&blocks[..2]));
+        assert_eq!(fs::metadata(block_file(&dir.0)).unwrap().len(), good_len);
+        // new blocks go right after the last good record
+        store.stage(&blocks[2]);
+        store.flush().unwrap();
+        let store = BlockStore::open(&dir.0).unwrap();
+        assert_eq!(hashes(&store.load_blocks().unwrap()), hashes(&blocks));
+    }
+
+    #[test]
+    fn record_with_a_bad_checksum_is_dropped() {
+        let dir = TempDir::new();
+        let blocks = blocks(2);
+        write(&dir.0, &blocks);
+        let mut bytes = fs::read(block_file(&dir.0)).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(block_file(&dir.0), &bytes).unwrap();
+
+        // the index still points at the record, reading it notices
+        let store = BlockStore::open(&dir.0).unwrap();
+        assert!(matches!(
+            store.read_block(&blocks[1].hash()),
+            Err(BtcError::InvalidStorage)
+        ));
+        // a rescan stops at it and cuts it off
+        fs::remove_file(dir.0.join(INDEX_FILE)).unwrap();
+        let store = BlockStore::open(&dir.0).unwrap();
+        assert_eq!(hashes(&store.load_blocks().unwrap()), hashes(&blocks[..1]));
+        assert!(store.read_block(&blocks[1].hash()).unwrap().is_none());
+    }
+
+    #[test]
+    fn missing_index_is_rebuilt_from_the_block_files() {
+        let dir = TempDir::new();
+        let blocks = blocks(3);
+        write(&dir.0, &blocks);
+        fs::remove_file(dir.0.join(INDEX_FILE)).unwrap();
+
+        let store = BlockStore::open(&dir.0).unwrap();
+        assert_eq!(hashes(&store.load_blocks().unwrap()), hashes(&blocks));
+        assert!(dir.0.join(INDEX_FILE).exists());
+    }
+
+    #[test]
+    fn unreadable_index_is_rebuilt_from_the_block_files() {
+        let dir = TempDir::new();
+        let blocks = blocks(2);
+        write(&dir.0, &blocks);
+        fs::write(dir.0.join(INDEX_FILE), b"not an index").unwrap();
+
+        let store = BlockStore::open(&dir.0).unwrap();
+        assert_eq!(hashes(&store.load_blocks().unwrap()), hashes(&blocks));
+    }
+
+    #[test]
+    fn stale_index_picks_up_later_records() {
+        let dir = TempDir::new();
+        let blocks = blocks(3);
+        write(&dir.0, &blocks[..1]);
+        let stale_index = fs::read(dir.0.join(INDEX_FILE)).unwrap();
+        write(&dir.0, &blocks[1..]);
+        // as if the process died between writing blocks and the index
+        fs::write(dir.0.join(INDEX_FILE), stale_index).unwrap();
+
+        let store = BlockStore::open(&dir.0).unwrap();
+        assert_eq!(store.len(), 3);
+        assert_eq!(hashes(&store.load_blocks().unwrap()), hashes(&blocks));
+    }
+}
@@ -1,20 +1,75 @@
 use crate::U256;
-use crate::crypto::{PublicKey, Signature};
+use crate::clock::{Clock, SystemClock};
+use crate::crypto::{PrivateKey, PublicKey, Signature};
+use crate::encode::{CompactSize, Encodable};
 use crate::error::{BtcError, Result};
+use crate::mempool::Mempool;
+use crate::params::ChainParams;
+use crate::script::{Script, ScriptContext, verify_script};
 use crate::sha256::Hash;
-use crate::utils::MerkleRoot;
-use bigdecimal::BigDecimal;
-use chrono::{DateTime, Utc};
+use crate::storage::BlockStore;
+use crate::utils::{CompactTarget, MerkleRoot};
+use chrono::{DateTime, Duration, Utc};
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
 use uuid::Uuid;
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BlockChain {
+    params: ChainParams,
     blocks: Vec<Block>,
-    target: U256,
-    utxos: HashMap<Hash, TransactionOutput>,
-    #[serde(default, skip_serializing)]
-    mempool: Vec<Transaction>,
+    target: CompactTarget,
+    utxos: HashMap<OutPoint, Utxo>,
+    #[serde(skip)]
+    mempool: Mempool,
+    #[serde(skip)]
+    storage: Option<BlockStore>,
+    /// every known block, including the ones on side branches
+    #[serde(default)]
+    block_tree: HashMap<Hash, BlockTreeEntry>,
+    /// undo record for every block of the active chain
+    #[serde(default)]
+    undo: Vec<BlockUndo>,
+    #[serde(skip, default = "system_clock")]
+    clock: Arc<dyn Clock>,
+}
+
+fn system_clock() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+/// Where `BlockChain::add_block` put a block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockStatus {
+    /// It extends the active chain
+    Connected,
+    /// It is on a side branch with no more work than the active chain
+    SideBranch,
+    /// Its branch became the active chain
+    Reorganized {
+        /// Blocks of the old branch taken off the chain
+        disconnected: usize,
+        /// Blocks of the new branch put on it
+        connected: usize,
+    },
+}
+
+/// What is needed to disconnect a block again
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BlockUndo {
+    /// Outputs spent by the block, in the order they were spent
+    pub spent_outputs: Vec<(OutPoint, Utxo)>,
+    /// Target before the block was added
+    pub target: CompactTarget,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BlockTreeEntry {
+    block: Block,
+    height: u64,
+    /// Work of this block and all of its ancestors
+    chain_work: U256,
 }
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Block {
@@ -31,85 +86,654 @@ pub struct BlockHeader {
     pub prev_block_hash: Hash,
     /// Markl root of the blocks transaction
     pub markle_root: MerkleRoot,
-    /// Target
-    pub target: U256,
+    /// Target the hash has to meet, in compact form
+    pub bits: CompactTarget,
 }
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Transaction {
     pub inputs: Vec<TransactionInput>,
     pub outputs: Vec<TransactionOutput>,
+    /// Earliest block the transaction may be included in, ignored
+    /// when every input is final
+    pub lock_time: LockTime,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct TransactionOutput {
     pub value: u64,
     pub unique_id: Uuid,
-    pub public_key: PublicKey,
+    /// Conditions for spending the output
+    pub script_pubkey: Script,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct TransactionInput {
-    pub prev_transaction_output_hash: Hash,
-    pub signature: Signature,
+    /// Output being spent
+    pub previous_output: OutPoint,
+    /// Data satisfying the spent output's script, usually signatures
+    pub script_sig: Script,
+    /// How long after the spent output confirmed it may be spent
+    pub sequence: Sequence,
+}
+
+/// Absolute timelock of a transaction
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum LockTime {
+    /// Valid in any block
+    #[default]
+    None,
+    /// Valid from the block at this height on
+    Height(u64),
+    /// Valid once the previous block's timestamp reaches this time
+    Time(DateTime<Utc>),
+}
+
+/// Relative timelock of an input, counted from the block that
+/// confirmed the spent output
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Sequence {
+    /// Spendable right away. if every input is final the transaction's
+    /// lock time is not enforced either
+    #[default]
+    Final,
+    /// Spendable this many blocks after the output
+    Blocks(u32),
+    /// Spendable once the previous block is this many seconds newer
+    /// than the output's block
+    Seconds(u32),
+}
+
+/// An unspent output and where it was confirmed
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Utxo {
+    pub output: TransactionOutput,
+    /// Height of the block that created the output
+    pub height: u64,
+    /// Timestamp of that block
+    pub time: DateTime<Utc>,
+    /// Created by a coinbase transaction
+    pub coinbase: bool,
+}
+
+/// The block a transaction is checked for
+#[derive(Clone, Copy, Debug)]
+pub struct NextBlock {
+    pub height: u64,
+    /// Timestamp of the block before it, time locks are measured
+    /// against it as the new block's own timestamp is up to the miner
+    pub prev_time: DateTime<Utc>,
+}
+
+/// Points at a single transaction output: the transaction that
+/// created it and the output's index in it
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct OutPoint {
+    pub txid: Hash,
+    pub vout: u32,
+}
+
+impl OutPoint {
+    pub fn new(txid: Hash, vout: u32) -> Self {
+        OutPoint { txid, vout }
+    }
+}
+
+// lock times below this are heights in scripts, above it unix timestamps
+const LOCK_TIME_THRESHOLD: i64 = 500_000_000;
+// set in a script number for sequences counting seconds
+const SEQUENCE_SECONDS_FLAG: i64 = 1 << 32;
+
+impl LockTime {
+    /// whether a transaction with this lock time fits into `next_block`
+    pub fn is_satisfied(self, next_block: &NextBlock) -> bool {
+        match self {
+            LockTime::None => true,
+            LockTime::Height(height) => next_block.height >= height,
+            LockTime::Time(time) => next_block.prev_time >= time,
+        }
+    }
+
+    /// as a script number, heights below 500 million and unix
+    /// timestamps above
+    pub fn to_number(self) -> i64 {
+        match self {
+            LockTime::None => 0,
+            LockTime::Height(height) => height as i64,
+            LockTime::Time(time) => time.timestamp(),
+        }
+    }
+
+    /// whether this lock time is at least the script number `number`,
+    /// only heights compare with heights and times with times
+    pub fn reaches(self, number: i64) -> bool {
+        match self {
+            LockTime::None => false,
+            LockTime::Height(_) => number < LOCK_TIME_THRESHOLD && self.to_number() >= number,
+            LockTime::Time(_) => number >= LOCK_TIME_THRESHOLD && self.to_number() >= number,
+        }
+    }
+}
+
+impl Utxo {
+    /// whether the output may be spent in the block at `next_height`.
+    /// coinbase outputs have to wait so a reorg dropping their block
+    /// does not take spends of them along
+    pub fn is_mature(&self, next_height: u64, params: &ChainParams) -> bool {
+        !self.coinbase || next_height >= self.height + params.coinbase_maturity
+    }
+}
+
+impl Sequence {
+    /// whether an input with this sequence may spend `utxo` in `next_block`
+    pub fn is_satisfied(self, utxo: &Utxo, next_block: &NextBlock) -> bool {
+        match self {
+            Sequence::Final => true,
+            Sequence::Blocks(blocks) => next_block.height >= utxo.height + blocks as u64,
+            Sequence::Seconds(seconds) => {
+                next_block.prev_time >= utxo.time + Duration::seconds(seconds as i64)
+            }
+        }
+    }
+
+    /// as a script number, seconds are flagged with bit 32
+    pub fn to_number(self) -> i64 {
+        match self {
+            Sequence::Final => 0,
+            Sequence::Blocks(blocks) => blocks as i64,
+            Sequence::Seconds(seconds) => seconds as i64 | SEQUENCE_SECONDS_FLAG,
+        }
+    }
+
+    /// whether this sequence is at least the script number `number`
+    pub fn reaches(self, number: i64) -> bool {
+        match self {
+            Sequence::Final => false,
+            Sequence::Blocks(_) => {
+                number & SEQUENCE_SECONDS_FLAG == 0 && self.to_number() >= number
+            }
+            Sequence::Seconds(_) => {
+                number & SEQUENCE_SECONDS_FLAG != 0 && self.to_number() >= number
+            }
+        }
+    }
+}
+
+impl fmt::Display for OutPoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.txid, self.vout)
+    }
+}
+
+/// parses the `txid:vout` form produced by `Display`
+impl FromStr for OutPoint {
+    type Err = BtcError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (txid, vout) = s.split_once(':').ok_or(BtcError::InvalidTransactionInput)?;
+        Ok(OutPoint {
+            txid: txid.parse()?,
+            vout: vout
+                .parse()
+                .map_err(|_| BtcError::InvalidTransactionInput)?,
+        })
+    }
+}
+
+/// Which outputs a signature commits to
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SigHashMode {
+    /// Every output, nothing can be changed after signing
+    #[default]
+    All,
+    /// No outputs, anyone may decide where the value goes
+    None,
+    /// Only the output with the same index as the input
+    Single,
+}
+
+/// Which parts of the spending transaction a signature commits to
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct SigHashType {
+    pub mode: SigHashMode,
+    /// Commit to the signed input alone, so others can add inputs
+    pub anyone_can_pay: bool,
+}
+
+impl SigHashType {
+    pub const ALL: SigHashType = SigHashType {
+        mode: SigHashMode::All,
+        anyone_can_pay: false,
+    };
+
+    /// the byte appended to signatures, same values as in bitcoin
+    pub fn to_byte(self) -> u8 {
+        let mode = match self.mode {
+            SigHashMode::All => 0x01,
+            SigHashMode::None => 0x02,
+            SigHashMode::Single => 0x03,
+        };
+        if self.anyone_can_pay {
+            mode | 0x80
+        } else {
+            mode
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        let mode = match byte & !0x80 {
+            0x01 => SigHashMode::All,
+            0x02 => SigHashMode::None,
+            0x03 => SigHashMode::Single,
+            _ => return None,
+        };
+        Some(SigHashType {
+            mode,
+            anyone_can_pay: byte & 0x80 != 0,
+        })
+    }
+}
+
+// what actually gets hashed and signed for an input
+struct SigHashPreimage<'a> {
+    sighash_type: SigHashType,
+    /// Spent outputs and sequences of the committed inputs
+    inputs: Vec<(OutPoint, Sequence)>,
+    input_index: usize,
+    /// The output being spent, so its value is signed as well
+    spent_output: &'a TransactionOutput,
+    outputs: &'a [TransactionOutput],
+    lock_time: LockTime,
+}
+
+impl Encodable for SigHashPreimage<'_> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.sighash_type.to_byte().encode(buf);
+        CompactSize(self.inputs.len() as u64).encode(buf);
+        for (outpoint, sequence) in &self.inputs {
+            outpoint.encode(buf);
+            sequence.encode(buf);
+        }
+        (self.input_index as u64).encode(buf);
+        self.spent_output.encode(buf);
+        self.outputs.encode(buf);
+        self.lock_time.encode(buf);
+    }
 }
 
 impl BlockChain {
-    pub fn new() -> Self {
-        BlockChain {
+    /// a chain holding only the genesis block of the network
+    pub fn new(params: ChainParams) -> Self {
+        let mut blockchain = BlockChain {
+            params,
             utxos: HashMap::new(),
-            target: crate::MINIMUM_TARGET,
+            target: CompactTarget::from_target(params.minimum_target),
             blocks: vec![],
-            mempool: vec![],
+            mempool: Mempool::default(),
+            storage: None,
+            block_tree: HashMap::new(),
+            undo: vec![],
+            clock: system_clock(),
+        };
+        // the genesis block is built from the params and checked like any
+        // other block, so nodes of a network always share the same root
+        let genesis = params.genesis_block();
+        // it starts from nothing at the easiest target
+        if genesis.header.prev_block_hash != Hash::zero()
+            || genesis.header.target() != params.minimum_target
+        {
+            panic!("invalid genesis block");
+        }
+        Self::check_block(&genesis, &params).expect("invalid genesis block");
+        genesis
+            .verify_transactions(blockchain.next_block(), &blockchain.utxos, &params)
+            .expect("invalid genesis block");
+        blockchain.insert_into_tree(&genesis, None);
+        blockchain.connect_block(genesis);
+        blockchain
+    }
+
+    pub fn params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    /// load the chain stored in `path`, or start an empty one there.
+    /// blocks are replayed through `add_block` so target and utxos end up
+    /// exactly as they were before the restart
+    pub fn open(path: impl AsRef<Path>, params: ChainParams) -> Result<Self> {
+        let storage = BlockStore::open(path)?;
+        let mut blockchain = BlockChain::new(params);
+        for block in storage.load_blocks()? {
+            blockchain.add_block(block)?;
+        }
+        blockchain.storage = Some(storage);
+        Ok(blockchain)
+    }
+
+    /// write every block added since the last flush to disk
+    pub fn flush(&mut self) -> Result<()> {
+        match &mut self.storage {
+            Some(storage) => storage.flush(),
+            None => Ok(()),
         }
     }
 
+    /// digest of the blocks of the active chain, in order. utxos and
+    /// target follow from them, so equal hashes mean equal chain state
     pub fn hash(&self) -> Hash {
-        Hash::hash(self)
+        Hash::hash(self.blocks.as_slice())
     }
 
-    pub fn add_block(&mut self, blocks: Block) -> Result<()> {
-        // check if the blocks is valid
-        if self.blocks.is_empty() {
-            // if this is the first blocks, check if the
-            // blocks's prev_block_hash is all zeroes
-            if blocks.header.prev_block_hash != Hash::zero() {
-                println!("zero hash");
-                return Err(BtcError::InvalidBlock);
-            }
+    /// validate a block and add it to the tree, switching to its branch
+    /// if that has the most work. says where the block ended up
+    pub fn add_block(&mut self, blocks: Block) -> Result<BlockStatus> {
+        let block_hash = blocks.hash();
+        if self.block_tree.contains_key(&block_hash) {
+            return Err(BtcError::BlockAlreadyKnown);
+        }
+        // the chain starts with the genesis block, so every block has a
+        // parent: the tip or any block on a side branch
+        if !self.block_tree.contains_key(&blocks.header.prev_block_hash) {
+            return Err(BtcError::UnknownParent);
+        }
+        Self::check_block(&blocks, &self.params)?;
+        if blocks.header.bits != self.next_target(&blocks.header.prev_block_hash) {
+            return Err(BtcError::WrongTarget);
+        }
+        self.check_timestamp(&blocks.header)?;
+        let status = if blocks.header.prev_block_hash == self.tip_hash() {
+            // Verify all transactions in the blocks
+            blocks.verify_transactions(self.next_block(), &self.utxos, &self.params)?;
+            let parent = blocks.header.prev_block_hash;
+            self.insert_into_tree(&blocks, Some(parent));
+            self.connect_block(blocks);
+            BlockStatus::Connected
         } else {
-            // if this is not the first blocks, check if the
-            // blocks's prev_block_hash is the hash of the lastblock
-            let last_block = self.blocks.last().unwrap();
-            if blocks.header.prev_block_hash != last_block.hash() {
-                println!("prev hash is wrong");
-                return Err(BtcError::InvalidBlock);
-            }
-            // check if the blocks's hash is less than the target
-            if !blocks.header.hash().matches_target(blocks.header.target) {
-                println!("does not match target");
-                return Err(BtcError::InvalidBlock);
+            let parent = blocks.header.prev_block_hash;
+            self.insert_into_tree(&blocks, Some(parent));
+            // switch over once the side branch has more work than the tip
+            if self.block_tree[&block_hash].chain_work > self.chain_work() {
+                self.reorganize(block_hash)?
+            } else {
+                BlockStatus::SideBranch
             }
-            // check if the blocks's merkle root is correct
-            let calculated_merkle_root = MerkleRoot::calculate(&blocks.transactions);
-            if calculated_merkle_root != blocks.header.markle_root {
-                println!("invalid merkle root");
-                return Err(BtcError::InvalidMerkleRoot);
+        };
+        if let Some(storage) = &mut self.storage {
+            storage.stage(&self.block_tree[&block_hash].block);
+        }
+        Ok(status)
+    }
+
+    // checks that only depend on the block itself,
+    // done for side branch blocks as well as for the tip
+    fn check_block(blocks: &Block, params: &ChainParams) -> Result<()> {
+        // check if the blocks's hash is less than the target
+        if !blocks.header.hash().matches_target(blocks.header.target())
+            || blocks.header.target() > params.minimum_target
+        {
+            return Err(BtcError::InsufficientProofOfWork);
+        }
+        // check if the blocks's merkle root is correct
+        let calculated_merkle_root = MerkleRoot::calculate(&blocks.transactions);
+        if calculated_merkle_root != blocks.header.markle_root {
+            return Err(BtcError::InvalidMerkleRoot);
+        }
+        // the last hash of an odd layer is paired with itself, so repeating
+        // the last transactions keeps the root. such a body is just as
+        // wrong for the header as one with a different root
+        let mut txids = HashSet::new();
+        if !blocks.transactions.iter().all(|tx| txids.insert(tx.hash())) {
+            return Err(BtcError::DuplicateTransaction);
+        }
+        Ok(())
+    }
+
+    // the timestamp has to be after the median time past of the parent,
+    // and not too far ahead of our clock
+    fn check_timestamp(&self, header: &BlockHeader) -> Result<()> {
+        if header.timestamp <= self.median_time_past(&header.prev_block_hash) {
+            return Err(BtcError::OldBlock);
+        }
+        let max_future = Duration::seconds(self.params.max_future_block_time as i64);
+        if header.timestamp > self.clock.now() + max_future {
+            return Err(BtcError::FutureBlock);
+        }
+        Ok(())
+    }
+
+    // append an already validated block to the active chain
+    fn connect_block(&mut self, blocks: Block) {
+        self.mempool.remove_for_block(&blocks);
+        let height = self.block_height();
+        let mut undo = Self::apply_transactions(&mut self.utxos, &blocks, height);
+        undo.target = self.target;
+        self.blocks.push(blocks);
+        self.undo.push(undo);
+        self.try_adjust_target();
+    }
+
+    /// remove the tip block from the active chain, restoring the utxo set
+    /// and target to what they were before it was added. its transactions
+    /// go back into the mempool, the block stays known as a side branch
+    pub fn disconnect_tip(&mut self) -> Result<Block> {
+        let blocks = self.disconnect_block()?;
+        self.mempool
+            .revalidate(&self.utxos, self.next_block(), &self.params);
+        self.mempool.readmit(
+            &blocks.transactions,
+            &self.utxos,
+            self.next_block(),
+            &self.params,
+            self.clock.as_ref(),
+        );
+        Ok(blocks)
+    }
+
+    // disconnect_tip without touching the mempool
+    fn disconnect_block(&mut self) -> Result<Block> {
+        // the genesis block stays
+        if self.blocks.len() <= 1 {
+            return Err(BtcError::InvalidBlockChain);
+        }
+        let (Some(blocks), Some(undo)) = (self.blocks.pop(), self.undo.pop()) else {
+            return Err(BtcError::InvalidBlockChain);
+        };
+        Self::revert_transactions(&mut self.utxos, &blocks.transactions, undo.spent_outputs);
+        self.target = undo.target;
+        Ok(blocks)
+    }
+
+    fn insert_into_tree(&mut self, blocks: &Block, parent: Option<Hash>) {
+        let (height, parent_work) = match parent {
+            Some(parent) => {
+                let parent = &self.block_tree[&parent];
+                (parent.height + 1, parent.chain_work)
             }
-            // check if the blocks's timestamp is after the
-            // last blocks's timestamp
-            if blocks.header.timestamp <= last_block.header.timestamp {
-                return Err(BtcError::InvalidBlock);
+            None => (0, U256::zero()),
+        };
+        self.block_tree.insert(
+            blocks.hash(),
+            BlockTreeEntry {
+                block: blocks.clone(),
+                height,
+                chain_work: parent_work.saturating_add(blocks.header.work()),
+            },
+        );
+    }
+
+    // forget an invalid block together with everything built on top of it
+    fn remove_from_tree(&mut self, hash: Hash) {
+        let mut invalid = vec![hash];
+        while let Some(hash) = invalid.pop() {
+            self.block_tree.remove(&hash);
+            invalid.extend(
+                self.block_tree
+                    .iter()
+                    .filter(|(_, entry)| entry.block.header.prev_block_hash == hash)
+                    .map(|(hash, _)| *hash),
+            );
+        }
+    }
+
+    // make the side branch ending in `new_tip` the active chain. the old
+    // branch is restored if any block of the new one turns out invalid
+    fn reorganize(&mut self, new_tip: Hash) -> Result<BlockStatus> {
+        // walk back from the new tip until we hit the active chain
+        let mut branch = vec![];
+        let mut hash = new_tip;
+        while !self.is_in_active_chain(&hash) {
+            let entry = &self.block_tree[&hash];
+            branch.push(entry.block.clone());
+            hash = entry.block.header.prev_block_hash;
+        }
+        branch.reverse();
+        let fork_height = self.block_tree[&hash].height as usize;
+
+        let saved_mempool = self.mempool.clone();
+        let mut disconnected = vec![];
+        while self.blocks.len() > fork_height + 1 {
+            disconnected.push(self.disconnect_block()?);
+        }
+        disconnected.reverse();
+
+        for (connected, block) in branch.iter().enumerate() {
+            if let Err(e) = block.verify_transactions(self.next_block(), &self.utxos, &self.params)
+            {
+                // roll back to the old branch
+                for _ in 0..connected {
+                    self.disconnect_block()?;
+                }
+                for block in disconnected {
+                    self.connect_block(block);
+                }
+                self.mempool = saved_mempool;
+                self.remove_from_tree(block.hash());
+                return Err(e);
             }
-            // Verify all transactions in the blocks
-            blocks.verify_transactions(self.block_height(), &self.utxos)?;
+            self.connect_block(block.clone());
         }
-        let block_transaction: HashSet<_> =
-            blocks.transactions.iter().map(|tx| tx.hash()).collect();
+
+        // pool entries may spend outputs of the old branch, and its
+        // transactions go back into the pool unless the new one has them
         self.mempool
-            .retain(|tx| !block_transaction.contains(&tx.hash()));
-        self.blocks.push(blocks);
-        self.try_adjust_target();
-        Ok(())
+            .revalidate(&self.utxos, self.next_block(), &self.params);
+        self.mempool.readmit(
+            disconnected.iter().flat_map(|block| &block.transactions),
+            &self.utxos,
+            self.next_block(),
+            &self.params,
+            self.clock.as_ref(),
+        );
+        Ok(BlockStatus::Reorganized {
+            disconnected: disconnected.len(),
+            connected: branch.len(),
+        })
+    }
+
+    pub fn is_in_active_chain(&self, hash: &Hash) -> bool {
+        match self.block_tree.get(hash) {
+            Some(entry) => self
+                .blocks
+                .get(entry.height as usize)
+                .is_some_and(|block| block.hash() == *hash),
+            None => false,
+        }
+    }
+
+    pub fn tip_hash(&self) -> Hash {
+        self.blocks
+            .last()
+            .map(|block| block.hash())
+            .unwrap_or_else(Hash::zero)
+    }
+
+    /// timestamps of the block `hash` and the blocks before it, newest first
+    pub fn timestamps_from<'a>(
+        &'a self,
+        hash: &Hash,
+    ) -> impl Iterator<Item = DateTime<Utc>> + use<'a> {
+        std::iter::successors(self.block_tree.get(hash), |entry| {
+            self.block_tree.get(&entry.block.header.prev_block_hash)
+        })
+        .map(|entry| entry.block.header.timestamp)
+    }
+
+    /// median timestamp of the block `hash` and the blocks before it, a
+    /// block on top of it has to be younger
+    pub fn median_time_past(&self, hash: &Hash) -> DateTime<Utc> {
+        self.params.median_time_past(self.timestamps_from(hash))
+    }
+
+    pub fn clock(&self) -> &dyn Clock {
+        self.clock.as_ref()
+    }
+
+    /// use `clock` instead of the system clock from now on
+    pub fn set_clock(&mut self, clock: impl Clock + 'static) {
+        self.clock = Arc::new(clock);
+    }
+
+    /// hash of the block every chain of the network starts with
+    pub fn genesis_hash(&self) -> Hash {
+        self.blocks[0].hash()
+    }
+
+    /// total work of the active chain
+    pub fn chain_work(&self) -> U256 {
+        self.chain_work_of(&self.tip_hash()).unwrap_or_default()
+    }
+
+    /// total work of the chain ending in the block `hash`, the chain with
+    /// the most of it is the active one
+    pub fn chain_work_of(&self, hash: &Hash) -> Option<U256> {
+        self.block_tree.get(hash).map(|entry| entry.chain_work)
+    }
+
+    /// hashes of the active chain going back from the tip, dense at first
+    /// and then doubling the step, always ending with the first block.
+    /// lets a peer find the fork point without sending the whole chain
+    pub fn block_locator(&self) -> Vec<Hash> {
+        let mut locator = vec![];
+        let mut step = 1;
+        let mut height = self.blocks.len();
+        while height > 0 {
+            locator.push(self.blocks[height - 1].hash());
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
+        }
+        if let Some(first) = self.blocks.first()
+            && locator.last() != Some(&first.hash())
+        {
+            locator.push(first.hash());
+        }
+        locator
+    }
+
+    /// up to `max` hashes of active chain blocks following the first
+    /// locator entry we know, or from the start if we know none of them
+    pub fn block_hashes_after(&self, locator: &[Hash], max: usize) -> Vec<Hash> {
+        let start = locator
+            .iter()
+            .find(|hash| self.is_in_active_chain(hash))
+            .map(|hash| self.block_tree[hash].height as usize + 1)
+            .unwrap_or(0);
+        self.blocks
+            .iter()
+            .skip(start)
+            .take(max)
+            .map(|block| block.hash())
+            .collect()
+    }
+
+    /// height of any known block, the first block being at height zero
+    pub fn block_height_of(&self, hash: &Hash) -> Option<u64> {
+        self.block_tree.get(hash).map(|entry| entry.height)
+    }
+
+    /// look up any known block, on the active chain or a side branch
+    pub fn get_block(&self, hash: &Hash) -> Option<&Block> {
+        self.block_tree.get(hash).map(|entry| &entry.block)
     }
 
     pub fn block_height(&self) -> u64 {
@@ -117,70 +741,171 @@ impl BlockChain {
     }
 
     pub fn rebuild_utxos(&mut self) {
-        for blocks in &self.blocks {
-            for transaction in blocks.transactions.iter().skip(1) {
-                for input in transaction.inputs.iter().skip(1) {
-                    self.utxos.remove(&input.prev_transaction_output_hash);
+        self.utxos.clear();
+        for (height, (blocks, undo)) in self.blocks.iter().zip(self.undo.iter_mut()).enumerate() {
+            let rebuilt = Self::apply_transactions(&mut self.utxos, blocks, height as u64);
+            undo.spent_outputs = rebuilt.spent_outputs;
+        }
+    }
+
+    // spend the inputs and add the outputs of every transaction of the
+    // block at `height`, including the coinbase. returns the spent outputs
+    fn apply_transactions(
+        utxos: &mut HashMap<OutPoint, Utxo>,
+        block: &Block,
+        height: u64,
+    ) -> BlockUndo {
+        let mut undo = BlockUndo::default();
+        for transaction in &block.transactions {
+            for input in &transaction.inputs {
+                if let Some(spent) = utxos.remove(&input.previous_output) {
+                    undo.spent_outputs.push((input.previous_output, spent));
                 }
-                for output in transaction.outputs.iter() {
-                    self.utxos.insert(transaction.hash(), output.clone());
+            }
+            let txid = transaction.hash();
+            for (vout, output) in transaction.outputs.iter().enumerate() {
+                let utxo = Utxo {
+                    output: output.clone(),
+                    height,
+                    time: block.header.timestamp,
+                    coinbase: transaction.inputs.is_empty(),
+                };
+                utxos.insert(OutPoint::new(txid, vout as u32), utxo);
+            }
+        }
+        undo
+    }
+
+    // the exact reverse of apply_transactions. transactions are undone
+    // back to front so outputs created and spent within the same block
+    // do not come back
+    fn revert_transactions(
+        utxos: &mut HashMap<OutPoint, Utxo>,
+        transactions: &[Transaction],
+        mut spent_outputs: Vec<(OutPoint, Utxo)>,
+    ) {
+        for transaction in transactions.iter().rev() {
+            let txid = transaction.hash();
+            for vout in 0..transaction.outputs.len() {
+                utxos.remove(&OutPoint::new(txid, vout as u32));
+            }
+            for input in transaction.inputs.iter().rev() {
+                if spent_outputs
+                    .last()
+                    .is_some_and(|(outpoint, _)| *outpoint == input.previous_output)
+                {
+                    let (outpoint, utxo) = spent_outputs.pop().unwrap();
+                    utxos.insert(outpoint, utxo);
                 }
             }
         }
     }
 
-    pub fn try_adjust_target(&mut self) {
-        if self.blocks.is_empty() {
-            return;
-        }
-        if self.blocks.len() % crate::DIFFICULTY_UPDATE_INTERVALS as usize != 0 {
-            return;
-        }
-        // measure the time that it took to mine the last crate::DIFFICULTY_UPDATE_INTERVALS with chrono
-        let start_time = self.blocks
-            [self.blocks.len() - crate::DIFFICULTY_UPDATE_INTERVALS as usize]
-            .header
-            .timestamp;
-        let end_time = self.blocks.last().unwrap().header.timestamp;
-        // diff in time for minnnig
-        let time_diff = start_time - end_time;
-        // time diff in second
-        let time_diff_seconds = time_diff.num_seconds();
-        // calculate the ideal number of second
-        let target_seconds = crate::IDEAL_BLOCK_TIME * crate::DIFFICULTY_UPDATE_INTERVALS;
-        //multiply the current target by actual time divided by ideal time
-        let new_target = BigDecimal::parse_bytes(&self.target.to_string().as_bytes(), 10)
-            .expect("Bug: impossible")
-            * (BigDecimal::from(time_diff_seconds) / BigDecimal::from(target_seconds));
-        let new_target_str = new_target
-            .to_string()
-            .split('.')
-            .next()
-            .expect("Bug: impossible")
-            .to_owned();
-        let new_target = U256::from_str_radix(&new_target_str, 10).expect("Bug: impossible");
-        //clamp new_target to be within the range of 4 * self.target and self.target / 4
-        let new_target = if new_target < self.target / 4 {
-            self.target / 4
-        } else if new_target > self.target * 4 {
-            self.target * 4
-        } else {
-            new_target
-        };
+    // blocks after the tip have to use the target that follows it
+    fn try_adjust_target(&mut self) {
+        self.target = self.next_target(&self.tip_hash());
+    }
 
-        // if the new_target is more than the minimum target set it to the minimum target
-        self.target = new_target.min(crate::MINIMUM_TARGET);
+    /// target a block on top of `parent` has to have
+    pub fn next_target(&self, parent: &Hash) -> CompactTarget {
+        match self.block_tree.get(parent) {
+            Some(entry) => self.params.next_target(
+                entry.height + 1,
+                entry.block.header.bits,
+                self.timestamps_from(parent),
+            ),
+            None => CompactTarget::from_target(self.params.minimum_target),
+        }
     }
 
-    pub fn utxos(&self) -> &HashMap<Hash, TransactionOutput> {
+    pub fn utxos(&self) -> &HashMap<OutPoint, Utxo> {
         &self.utxos
     }
 
+    /// the block that would extend the active chain
+    pub fn next_block(&self) -> NextBlock {
+        NextBlock {
+            height: self.block_height(),
+            prev_time: self
+                .blocks
+                .last()
+                .map(|block| block.header.timestamp)
+                .unwrap_or(DateTime::UNIX_EPOCH),
+        }
+    }
+
+    /// assemble an unmined block on top of the current tip: mempool
+    /// transactions by fee rate and a coinbase paying subsidy plus fees
+    /// to `miner_pubkey`. the nonce still has to be found
+    pub fn create_block_template(&self, miner_pubkey: PublicKey) -> Block {
+        let mut transactions = vec![];
+        let mut miner_fees = 0;
+        for entry in self.mempool.sorted_by_fee_rate() {
+            // the mempool is kept valid against the utxo set, this only
+            // guards against it having gone stale
+            if let Ok(fee) = entry.transaction.fee(&self.utxos) {
+                miner_fees += fee;
+                transactions.push(entry.transaction.clone());
+            }
+        }
+        let coinbase = Transaction::new(
+            vec![],
+            vec![TransactionOutput {
+                value: self.params.block_reward(self.block_height()) + miner_fees,
+                unique_id: Uuid::new_v4(),
+                script_pubkey: Script::p2pkh(&miner_pubkey),
+            }],
+        );
+        transactions.insert(0, coinbase);
+        // the timestamp has to pass the median time past even if the
+        // clock is behind
+        let median_time_past = self.median_time_past(&self.tip_hash());
+        let timestamp = self
+            .clock
+            .now()
+            .max(median_time_past + Duration::seconds(1));
+        let header = BlockHeader::new(
+            timestamp,
+            0,
+            self.tip_hash(),
+            MerkleRoot::calculate(&transactions),
+            self.target,
+        );
+        Block::new(header, transactions)
+    }
+
+    pub fn target(&self) -> U256 {
+        self.target.to_target()
+    }
+
+    pub fn mempool(&self) -> &Mempool {
+        &self.mempool
+    }
+
+    /// validate a transaction against the current utxo set and add it
+    /// to the mempool
+    pub fn add_to_mempool(&mut self, transaction: Transaction) -> Result<()> {
+        // the pool holds transactions for the next block
+        self.mempool.add(
+            transaction,
+            &self.utxos,
+            self.next_block(),
+            &self.params,
+            self.clock.as_ref(),
+        )
+    }
+
     pub fn blocks(&self) -> impl Iterator<Item = &Block> {
         self.blocks.iter()
     }
 }
 
+impl Default for BlockChain {
+    fn default() -> Self {
+        Self::new(ChainParams::default())
+    }
+}
+
 impl Block {
     pub fn new(header: BlockHeader, transactions: Vec<Transaction>) -> Self {
         Block {
@@ -189,84 +914,64 @@ impl Block {
         }
     }
 
-    pub fn calculate_miner_fees(&self, utxos: &HashMap<Hash, TransactionOutput>) -> Result<u64> {
-        let mut inputs: HashMap<Hash, TransactionOutput> = HashMap::new();
-        let mut outputs: HashMap<Hash, TransactionOutput> = HashMap::new();
+    pub fn calculate_miner_fees(&self, utxos: &HashMap<OutPoint, Utxo>) -> Result<u64> {
+        let mut inputs: HashSet<OutPoint> = HashSet::new();
+        let mut miner_fees = 0;
         // check transsctions after coinbase
         for transaction in self.transactions.iter().skip(1) {
             for input in &transaction.inputs {
-                // for input in transaction.inputs.iter().skip(1) //a more prefferd way by me
-                // input do not contain the values of outputs so we need to match inputs to outputs
-                let prev_outputs = utxos.get(&input.prev_transaction_output_hash);
-                if prev_outputs.is_none() {
-                    return Err(BtcError::InvalidTransaction);
-                }
-                let prev_output = prev_outputs.unwrap();
-                if inputs.contains_key(&input.prev_transaction_output_hash) {
-                    return Err(BtcError::InvalidTransaction);
-                }
-                inputs.insert(input.prev_transaction_output_hash, prev_output.clone());
-            }
-            for output in transaction.outputs.iter().skip(1) {
-                if outputs.contains_key(&output.hash()) {
+                // prevents same-blocks double-spending
+                if !inputs.insert(input.previous_output) {
                     return Err(BtcError::InvalidTransaction);
                 }
-                outputs.insert(output.hash(), output.clone());
             }
+            miner_fees = transaction
+                .fee(utxos)?
+                .checked_add(miner_fees)
+                .ok_or(BtcError::InvalidTransaction)?;
         }
-        let input_value: u64 = inputs.values().map(|output| output.value).sum();
-        let output_value: u64 = outputs.values().map(|output| output.value).sum();
-        Ok(input_value - output_value)
+        Ok(miner_fees)
     }
 
+    /// a block is identified by its header alone, the merkle root already
+    /// commits to the transactions. this lets a header chain be checked
+    /// before any block body is downloaded
     pub fn hash(&self) -> Hash {
-        Hash::hash(self)
+        self.header.hash()
     }
 
     pub fn verify_transactions(
         &self,
-        predicted_block_height: u64,
-        utxos: &HashMap<Hash, TransactionOutput>,
+        next_block: NextBlock,
+        utxos: &HashMap<OutPoint, Utxo>,
+        params: &ChainParams,
     ) -> Result<()> {
-        let mut inputs: HashMap<Hash, TransactionOutput> = HashMap::new();
         // reject completely empty blocks
         if self.transactions.is_empty() {
-            println!("Empty transaction");
             return Err(BtcError::InvalidTransaction);
         }
         // verify coinbase transaction
-        self.verify_coinbase_transaction(predicted_block_height, utxos)?;
+        self.verify_coinbase_transaction(next_block.height, utxos, params)?;
+        // a transaction with the txid of one that still has unspent
+        // outputs would overwrite them, and undoing the block could not
+        // bring them back
+        for transaction in &self.transactions {
+            let txid = transaction.hash();
+            if (0..transaction.outputs.len())
+                .any(|vout| utxos.contains_key(&OutPoint::new(txid, vout as u32)))
+            {
+                return Err(BtcError::InvalidTransaction);
+            }
+        }
+        let mut inputs: HashSet<OutPoint> = HashSet::new();
         for transaction in self.transactions.iter().skip(1) {
-            let mut input_value = 0;
-            let mut otput_value = 0;
-            for input in transaction.inputs.iter().skip(1) {
-                let prev_outputs = utxos.get(&input.prev_transaction_output_hash);
-                if prev_outputs.is_none() {
-                    return Err(BtcError::InvalidTransaction);
-                }
-                let prev_output = prev_outputs.unwrap();
+            for input in &transaction.inputs {
                 // prevents same-blocks double-spending
-                if inputs.contains_key(&input.prev_transaction_output_hash) {
+                if !inputs.insert(input.previous_output) {
                     return Err(BtcError::InvalidTransaction);
                 }
-                // check if the signature is valid
-                if !input
-                    .signature
-                    .verify(&input.prev_transaction_output_hash, &prev_output.public_key)
-                {
-                    return Err(BtcError::InvalidSignature);
-                }
-                input_value += prev_output.value;
-                inputs.insert(input.prev_transaction_output_hash, prev_output.clone());
-            }
-            for output in transaction.outputs.iter().skip(1) {
-                otput_value += output.value;
-            }
-            // its fine for output value to be less than input value
-            // as difference is fee for miners
-            if input_value < otput_value {
-                return Err(BtcError::InvalidTransaction);
             }
+            transaction.verify(utxos, &next_block, params)?;
         }
         Ok(())
     }
@@ -274,25 +979,30 @@ impl Block {
     pub fn verify_coinbase_transaction(
         &self,
         predicted_block_height: u64,
-        utxos: &HashMap<Hash, TransactionOutput>,
+        utxos: &HashMap<OutPoint, Utxo>,
+        params: &ChainParams,
     ) -> Result<()> {
         //coinbase transaction is the first transaction in the blocks
         let coinbase_transaction = &self.transactions[0];
-        if coinbase_transaction.inputs.len() != 0 {
+        if !coinbase_transaction.inputs.is_empty() {
             return Err(BtcError::InvalidTransaction);
         }
-        if coinbase_transaction.outputs.len() == 0 {
+        if coinbase_transaction.outputs.is_empty() {
             return Err(BtcError::InvalidTransaction);
         }
         let miner_fees = self.calculate_miner_fees(utxos)?;
-        let block_reward = crate::INITIAL_REWARD * 10u64.pow(8)
-            / 2u64.pow((predicted_block_height / crate::HALVING_INTERVAL) as u32);
-        let total_coinbase_outputs: u64 = coinbase_transaction
+        let block_reward = params
+            .block_reward(predicted_block_height)
+            .checked_add(miner_fees)
+            .ok_or(BtcError::InvalidTransaction)?;
+        // outputs adding up past u64::MAX would otherwise wrap around to
+        // any value the miner likes
+        let total_coinbase_outputs = coinbase_transaction
             .outputs
             .iter()
-            .map(|output| output.value)
-            .sum();
-        if total_coinbase_outputs != block_reward + miner_fees {
+            .try_fold(0u64, |sum, output| sum.checked_add(output.value))
+            .ok_or(BtcError::InvalidTransaction)?;
+        if total_coinbase_outputs != block_reward {
             return Err(BtcError::InvalidTransaction);
         }
         Ok(())
@@ -305,23 +1015,44 @@ impl BlockHeader {
         nonce: u64,
         prev_block_hash: Hash,
         markle_root: MerkleRoot,
-        target: U256,
+        bits: CompactTarget,
     ) -> Self {
         BlockHeader {
             timestamp,
             nonce,
             prev_block_hash,
             markle_root,
-            target,
+            bits,
         }
     }
 
+    pub fn target(&self) -> U256 {
+        self.bits.to_target()
+    }
+
     pub fn hash(&self) -> Hash {
         Hash::hash(self)
     }
-    pub fn mine(&mut self, steps: usize) -> bool {
+
+    /// expected number of hashes needed to mine a block at this target
+    pub fn work(&self) -> U256 {
+        // 2^256 / (target + 1), written so that it fits into 256 bits.
+        // a zero target can not be met, so it is worth nothing
+        let target = self.target();
+        if target.is_zero() {
+            return U256::zero();
+        }
+        match target.checked_add(U256::one()) {
+            Some(divisor) => (!target / divisor) + U256::one(),
+            None => U256::one(),
+        }
+    }
+
+    /// try `steps` nonces, the timestamp is taken from `clock` again
+    /// once all of them are used up
+    pub fn mine(&mut self, steps: usize, clock: &dyn Clock) -> bool {
         // if the blocks already matches target return early
-        if self.hash().matches_target(self.target) {
+        if self.hash().matches_target(self.target()) {
             return true;
         }
         for _ in 0..steps {
@@ -329,9 +1060,9 @@ impl BlockHeader {
                 self.nonce = new_nonce;
             } else {
                 self.nonce = 0;
-                self.timestamp = Utc::now();
+                self.timestamp = clock.now();
             }
-            if self.hash().matches_target(self.target) {
+            if self.hash().matches_target(self.target()) {
                 return true;
             }
         }
@@ -342,13 +1073,171 @@ impl BlockHeader {
 impl Transaction {
     pub fn new(inputs: Vec<TransactionInput>, outputs: Vec<TransactionOutput>) -> Self {
         Transaction {
-            inputs: inputs,
-            outputs: outputs,
+            inputs,
+            outputs,
+            lock_time: LockTime::None,
         }
     }
     pub fn hash(&self) -> Hash {
         Hash::hash(self)
     }
+
+    /// encoded size in bytes, used for fee rates
+    pub fn size(&self) -> usize {
+        self.to_bytes().len()
+    }
+
+    /// value of the spent outputs minus value of the new ones
+    pub fn fee(&self, utxos: &HashMap<OutPoint, Utxo>) -> Result<u64> {
+        let mut input_value: u64 = 0;
+        for input in &self.inputs {
+            // input do not contain the values of outputs so we need to match inputs to outputs
+            let prev_output = utxos
+                .get(&input.previous_output)
+                .ok_or(BtcError::InvalidTransaction)?;
+            input_value = input_value
+                .checked_add(prev_output.output.value)
+                .ok_or(BtcError::InvalidTransaction)?;
+        }
+        let output_value = self
+            .outputs
+            .iter()
+            .try_fold(0u64, |sum, output| sum.checked_add(output.value))
+            .ok_or(BtcError::InvalidTransaction)?;
+        // its fine for output value to be less than input value
+        // as difference is fee for miners
+        input_value
+            .checked_sub(output_value)
+            .ok_or(BtcError::InvalidTransaction)
+    }
+
+    /// the hash signed by input `index` of this transaction, which spends
+    /// `spent_output`. unlocking scripts are left out, they can not sign
+    /// themselves
+    pub fn signature_hash(
+        &self,
+        index: usize,
+        spent_output: &TransactionOutput,
+        sighash_type: SigHashType,
+    ) -> Result<Hash> {
+        let input = self
+            .inputs
+            .get(index)
+            .ok_or(BtcError::InvalidTransactionInput)?;
+        let (inputs, input_index) = if sighash_type.anyone_can_pay {
+            (vec![(input.previous_output, input.sequence)], 0)
+        } else {
+            let inputs = self
+                .inputs
+                .iter()
+                .map(|input| (input.previous_output, input.sequence))
+                .collect();
+            (inputs, index)
+        };
+        let outputs = match sighash_type.mode {
+            SigHashMode::All => &self.outputs[..],
+            SigHashMode::None => &[],
+            SigHashMode::Single => {
+                // there has to be an output to commit to
+                let output = self
+                    .outputs
+                    .get(index)
+                    .ok_or(BtcError::InvalidTransactionInput)?;
+                std::slice::from_ref(output)
+            }
+        };
+        Ok(Hash::hash(&SigHashPreimage {
+            sighash_type,
+            inputs,
+            input_index,
+            spent_output,
+            outputs,
+            lock_time: self.lock_time,
+        }))
+    }
+
+    /// signature for input `index`, which spends `spent_output`, ready
+    /// to be pushed by an unlocking script
+    pub fn sign_input(
+        &self,
+        index: usize,
+        spent_output: &TransactionOutput,
+        sighash_type: SigHashType,
+        private_key: &mut PrivateKey,
+    ) -> Result<Vec<u8>> {
+        let sighash = self.signature_hash(index, spent_output, sighash_type)?;
+        let mut signature = Signature::sign_out(&sighash, private_key).to_bytes();
+        signature.push(sighash_type.to_byte());
+        Ok(signature)
+    }
+
+    /// check a non-coinbase transaction going into `next_block` against
+    /// the utxo set and return its fee
+    pub fn verify(
+        &self,
+        utxos: &HashMap<OutPoint, Utxo>,
+        next_block: &NextBlock,
+        params: &ChainParams,
+    ) -> Result<u64> {
+        if self.inputs.is_empty() || self.outputs.is_empty() {
+            return Err(BtcError::InvalidTransaction);
+        }
+        self.check_locks(utxos, next_block, params)?;
+        let mut inputs: HashSet<OutPoint> = HashSet::new();
+        for (index, input) in self.inputs.iter().enumerate() {
+            let prev_output = utxos
+                .get(&input.previous_output)
+                .ok_or(BtcError::InvalidTransaction)?;
+            // the same output can not be spent twice
+            if !inputs.insert(input.previous_output) {
+                return Err(BtcError::InvalidTransaction);
+            }
+            let context = ScriptContext {
+                transaction: self,
+                input_index: index,
+                spent_output: &prev_output.output,
+            };
+            verify_script(
+                &input.script_sig,
+                &prev_output.output.script_pubkey,
+                &context,
+            )?;
+        }
+        self.fee(utxos)
+    }
+
+    /// whether `lock_time` is enforced, same rule as in bitcoin: only if
+    /// at least one input is not final
+    pub fn is_lock_time_enabled(&self) -> bool {
+        self.inputs
+            .iter()
+            .any(|input| input.sequence != Sequence::Final)
+    }
+
+    /// check the lock time, the relative locks of the inputs and that
+    /// spent coinbase outputs matured
+    pub fn check_locks(
+        &self,
+        utxos: &HashMap<OutPoint, Utxo>,
+        next_block: &NextBlock,
+        params: &ChainParams,
+    ) -> Result<()> {
+        if self.is_lock_time_enabled() && !self.lock_time.is_satisfied(next_block) {
+            return Err(BtcError::TransactionLocked);
+        }
+        for input in &self.inputs {
+            let utxo = utxos
+                .get(&input.previous_output)
+                .ok_or(BtcError::InvalidTransaction)?;
+            if !input.sequence.is_satisfied(utxo, next_block) {
+                return Err(BtcError::TransactionLocked);
+            }
+            if !utxo.is_mature(next_block.height, params) {
+                return Err(BtcError::ImmatureCoinbase);
+            }
+        }
+        Ok(())
+    }
 }
 
 impl TransactionOutput {
@@ -356,3 +1245,690 @@ impl TransactionOutput {
         Hash::hash(self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::TransactionBuilder;
+    use crate::clock::FixedClock;
+
+    // regtest blocks are found within a few thousand hashes
+    fn solve(mut block: Block) -> Block {
+        block.header.markle_root = MerkleRoot::calculate(&block.transactions);
+        while !block.header.mine(1000, &SystemClock) {}
+        block
+    }
+
+    // mine the next block paying `key` and add it
+    fn mine_block(blockchain: &mut BlockChain, key: &PrivateKey) -> Block {
+        let block = solve(blockchain.create_block_template(key.public_key()));
+        blockchain.add_block(block.clone()).unwrap();
+        block
+    }
+
+    fn utxo_set(blockchain: &BlockChain) -> HashMap<OutPoint, (Hash, u64)> {
+        blockchain
+            .utxos()
+            .iter()
+            .map(|(outpoint, utxo)| (*outpoint, (utxo.output.hash(), utxo.height)))
+            .collect()
+    }
+
+    // a chain whose first coinbase paying `key` can be spent
+    fn chain_with_mature_coinbase(key: &PrivateKey) -> BlockChain {
+        let mut blockchain = BlockChain::new(ChainParams::regtest());
+        for _ in 0..=blockchain.params().coinbase_maturity {
+            mine_block(&mut blockchain, key);
+        }
+        blockchain
+    }
+
+    fn spend(blockchain: &BlockChain, key: &PrivateKey, outpoint: OutPoint) -> Transaction {
+        let output = blockchain.utxos()[&outpoint].output.clone();
+        TransactionBuilder::new()
+            .add_utxos([(outpoint, output.clone())])
+            .add_key(key.clone())
+            .add_recipient(PrivateKey::new_key().public_key(), output.value / 2)
+            .build()
+            .unwrap()
+    }
+
+    fn coinbase_outpoint(block: &Block) -> OutPoint {
+        OutPoint::new(block.transactions[0].hash(), 0)
+    }
+
+    fn block_at(blockchain: &BlockChain, timestamp: DateTime<Utc>) -> Block {
+        let key = PrivateKey::new_key();
+        let mut block = blockchain.create_block_template(key.public_key());
+        block.header.timestamp = timestamp;
+        solve(block)
+    }
+
+    #[test]
+    fn block_with_the_wrong_target_is_rejected() {
+        let mut blockchain = BlockChain::new(ChainParams::regtest());
+        let key = PrivateKey::new_key();
+        let mut block = blockchain.create_block_template(key.public_key());
+        // harder than required is still wrong
+        block.header.bits = CompactTarget::from_target(blockchain.target() / U256::from(2));
+        assert!(matches!(
+            blockchain.add_block(solve(block)),
+            Err(BtcError::WrongTarget)
+        ));
+        assert_eq!(blockchain.block_height(), 1);
+    }
+
+    #[test]
+    fn block_hash_has_to_meet_its_target() {
+        let key = PrivateKey::new_key();
+        let mut blockchain = BlockChain::new(ChainParams::regtest());
+        let mut block = blockchain.create_block_template(key.public_key());
+        block.header.markle_root = MerkleRoot::calculate(&block.transactions);
+        block.header.bits = CompactTarget::from_target(U256::one());
+        assert!(matches!(
+            blockchain.add_block(block),
+            Err(BtcError::InsufficientProofOfWork)
+        ));
+    }
+
+    #[test]
+    fn block_with_a_transaction_twice_is_rejected() {
+        let key = PrivateKey::new_key();
+        let mut blockchain = BlockChain::new(ChainParams::regtest());
+        let mut block = blockchain.create_block_template(key.public_key());
+        block.transactions.push(block.transactions[0].clone());
+        assert!(matches!(
+            blockchain.add_block(solve(block)),
+            Err(BtcError::DuplicateTransaction)
+        ));
+    }
+
+    #[test]
+    fn timestamp_has_to_be_after_median_time_past() {
+        let params = ChainParams::regtest();
+        let start = params.genesis_timestamp;
+        let mut blockchain = BlockChain::new(params);
+        blockchain.set_clock(FixedClock(start + Duration::days(1)));
+        // timestamps jump around, their median still moves forward
+        for minutes in [10, 20, 15, 30, 18, 40, 19, 50, 45, 60] {
+            let block = block_at(&blockchain, start + Duration::minutes(minutes));
+            blockchain.add_block(block).unwrap();
+        }
+        let median_time_past = blockchain.median_time_past(&blockchain.tip_hash());
+        assert_eq!(median_time_past, start + Duration::minutes(20));
+
+        let at_median = block_at(&blockchain, median_time_past);
+        assert!(matches!(
+            blockchain.add_block(at_median),
+            Err(BtcError::OldBlock)
+        ));
+        // older than the tip, but after the median
+        let after_median = block_at(&blockchain, median_time_past + Duration::seconds(1));
+        assert!(blockchain.add_block(after_median).is_ok());
+    }
+
+    #[test]
+    fn timestamp_may_only_be_a_little_ahead_of_the_clock() {
+        let params = ChainParams::regtest();
+        let now = params.genesis_timestamp + Duration::days(1);
+        let max_future = Duration::seconds(params.max_future_block_time as i64);
+        let mut blockchain = BlockChain::new(params);
+        blockchain.set_clock(FixedClock(now));
+
+        let too_far = block_at(&blockchain, now + max_future + Duration::seconds(1));
+        assert!(matches!(
+            blockchain.add_block(too_far.clone()),
+            Err(BtcError::FutureBlock)
+        ));
+        let at_limit = block_at(&blockchain, now + max_future);
+        assert!(blockchain.add_block(at_limit).is_ok());
+
+        // the same block is fine once the clock caught up
+        let mut blockchain = BlockChain::new(params);
+        blockchain.set_clock(FixedClock(now + Duration::seconds(1)));
+        assert!(blockchain.add_block(too_far).is_ok());
+    }
+
+    #[test]
+    fn coinbase_outputs_wrapping_around_are_rejected() {
+        let mut blockchain = BlockChain::new(ChainParams::regtest());
+        let key = PrivateKey::new_key();
+        let mut block = blockchain.create_block_template(key.public_key());
+        let coinbase = &mut block.transactions[0];
+        let reward = coinbase.outputs[0].value;
+        // u64::MAX + reward + 1 wraps around to exactly the reward
+        let mut extra = coinbase.outputs[0].clone();
+        extra.value = reward + 1;
+        extra.unique_id = Uuid::new_v4();
+        coinbase.outputs[0].value = u64::MAX;
+        coinbase.outputs.push(extra);
+        assert!(matches!(
+            blockchain.add_block(solve(block)),
+            Err(BtcError::InvalidTransaction)
+        ));
+        assert_eq!(blockchain.block_height(), 1);
+    }
+
+    #[test]
+    fn branch_with_more_work_takes_over() {
+        let key = PrivateKey::new_key();
+        let mut blockchain = BlockChain::new(ChainParams::regtest());
+        let mut other = BlockChain::new(ChainParams::regtest());
+        let old_branch = [
+            mine_block(&mut blockchain, &key),
+            mine_block(&mut blockchain, &key),
+        ];
+        let new_branch: Vec<Block> = (0..3).map(|_| mine_block(&mut other, &key)).collect();
+
+        assert_eq!(
+            blockchain.add_block(new_branch[0].clone()).unwrap(),
+            BlockStatus::SideBranch
+        );
+        // the same work as the active chain is not enough
+        assert_eq!(
+            blockchain.add_block(new_branch[1].clone()).unwrap(),
+            BlockStatus::SideBranch
+        );
+        assert_eq!(blockchain.tip_hash(), old_branch[1].hash());
+        assert_eq!(
+            blockchain.add_block(new_branch[2].clone()).unwrap(),
+            BlockStatus::Reorganized {
+                disconnected: 2,
+                connected: 3
+            }
+        );
+        assert_eq!(blockchain.tip_hash(), other.tip_hash());
+        assert_eq!(blockchain.hash(), other.hash());
+        assert_eq!(utxo_set(&blockchain), utxo_set(&other));
+        // the old blocks are still known, just not active
+        assert!(!blockchain.is_in_active_chain(&old_branch[0].hash()));
+        assert!(blockchain.get_block(&old_branch[0].hash()).is_some());
+    }
+
+    #[test]
+    fn disconnecting_restores_the_utxo_set() {
+        let key = PrivateKey::new_key();
+        let mut blockchain = chain_with_mature_coinbase(&key);
+        let first = blockchain.blocks().nth(1).unwrap().clone();
+        let before = utxo_set(&blockchain);
+        let target = blockchain.target();
+
+        let transaction = spend(&blockchain, &key, coinbase_outpoint(&first));
+        blockchain.add_to_mempool(transaction.clone()).unwrap();
+        let block = mine_block(&mut blockchain, &key);
+        assert_eq!(block.transactions.len(), 2);
+        assert!(!blockchain.utxos().contains_key(&coinbase_outpoint(&first)));
+
+        let disconnected = blockchain.disconnect_tip().unwrap();
+        assert_eq!(disconnected.hash(), block.hash());
+        assert_eq!(utxo_set(&blockchain), before);
+        assert_eq!(blockchain.target(), target);
+        // the spend goes back into the mempool
+        assert!(
+            blockchain
+                .mempool()
+                .sorted_by_fee_rate()
+                .iter()
+                .any(|entry| entry.transaction.hash() == transaction.hash())
+        );
+    }
+
+    #[test]
+    fn genesis_block_can_not_be_disconnected() {
+        let mut blockchain = BlockChain::new(ChainParams::regtest());
+        assert!(blockchain.disconnect_tip().is_err());
+        assert_eq!(blockchain.block_height(), 1);
+    }
+
+    #[test]
+    fn failed_reorganization_keeps_the_old_tip() {
+        let key = PrivateKey::new_key();
+        let mut blockchain = BlockChain::new(ChainParams::regtest());
+        let mut other = BlockChain::new(ChainParams::regtest());
+        let old_tip = mine_block(&mut blockchain, &key);
+        let before = utxo_set(&blockchain);
+
+        // a valid block, then one paying itself too much. only the
+        // reorganization gets to check its transactions
+        let valid = mine_block(&mut other, &key);
+        let mut invalid = other.create_block_template(key.public_key());
+        invalid.transactions[0].outputs[0].value += 1;
+        let invalid = solve(invalid);
+
+        blockchain.add_block(valid.clone()).unwrap();
+        assert!(matches!(
+            blockchain.add_block(invalid.clone()),
+            Err(BtcError::InvalidTransaction)
+        ));
+        assert_eq!(blockchain.tip_hash(), old_tip.hash());
+        assert_eq!(utxo_set(&blockchain), before);
+        assert!(blockchain.get_block(&invalid.hash()).is_none());
+        assert!(blockchain.get_block(&valid.hash()).is_some());
+    }
+
+    #[test]
+    fn transaction_may_not_overwrite_unspent_outputs() {
+        let key = PrivateKey::new_key();
+        let mut blockchain = BlockChain::new(ChainParams::regtest());
+        let first = mine_block(&mut blockchain, &key);
+        let before = utxo_set(&blockchain);
+
+        // the same coinbase again, paying the same reward
+        let mut block = blockchain.create_block_template(key.public_key());
+        block.transactions[0] = first.transactions[0].clone();
+        assert!(matches!(
+            blockchain.add_block(solve(block)),
+            Err(BtcError::InvalidTransaction)
+        ));
+        assert_eq!(blockchain.tip_hash(), first.hash());
+        assert_eq!(utxo_set(&blockchain), before);
+    }
+
+    // a transaction with two inputs and two outputs, the first input
+    // spending an output locked to the returned key
+    fn two_in_two_out() -> (Transaction, TransactionOutput, PrivateKey) {
+        let key = PrivateKey::new_key();
+        let input = || TransactionInput {
+            previous_output: OutPoint::new(Hash::hash(&Uuid::new_v4()), 0),
+            script_sig: Script::default(),
+            sequence: Sequence::Final,
+        };
+        let output = |value| TransactionOutput {
+            value,
+            unique_id: Uuid::new_v4(),
+            script_pubkey: Script::p2pkh(&PrivateKey::new_key().public_key()),
+        };
+        let transaction = Transaction::new(vec![input(), input()], vec![output(1), output(2)]);
+        let spent_output = TransactionOutput {
+            value: 3,
+            unique_id: Uuid::new_v4(),
+            script_pubkey: Script::p2pk(&key.public_key()),
+        };
+        (transaction, spent_output, key)
+    }
+
+    // whether the signature on the first input survives `change`
+    fn still_valid(sighash_type: SigHashType, change: impl FnOnce(&mut Transaction)) -> bool {
+        let (mut transaction, spent_output, mut key) = two_in_two_out();
+        let signature = transaction
+            .sign_input(0, &spent_output, sighash_type, &mut key)
+            .unwrap();
+        transaction.inputs[0].script_sig = Script::unlock_with_signatures(vec![signature]);
+        change(&mut transaction);
+        let context = ScriptContext {
+            transaction: &transaction,
+            input_index: 0,
+            spent_output: &spent_output,
+        };
+        verify_script(
+            &transaction.inputs[0].script_sig,
+            &spent_output.script_pubkey,
+            &context,
+        )
+        .is_ok()
+    }
+
+    fn sighash(mode: SigHashMode, anyone_can_pay: bool) -> SigHashType {
+        SigHashType {
+            mode,
+            anyone_can_pay,
+        }
+    }
+
+    fn other_input_moved(transaction: &mut Transaction) {
+        transaction.inputs[1].previous_output.vout += 1;
+    }
+
+    fn input_added(transaction: &mut Transaction) {
+        let mut input = transaction.inputs[1].clone();
+        input.previous_output.vout += 1;
+        transaction.inputs.push(input);
+    }
+
+    #[test]
+    fn sighash_all_commits_to_everything() {
+        let all = SigHashType::ALL;
+        assert!(still_valid(all, |_| {}));
+        assert!(!still_valid(all, other_input_moved));
+        assert!(!still_valid(all, input_added));
+        assert!(!still_valid(all, |tx| tx.outputs[1].value += 1));
+        assert!(!still_valid(all, |tx| tx.outputs.truncate(1)));
+        assert!(!still_valid(all, |tx| tx.lock_time = LockTime::Height(1)));
+        // the sequence of another input is committed to as well
+        assert!(!still_valid(all, |tx| tx.inputs[1].sequence = Sequence::Blocks(1)));
+    }
+
+    #[test]
+    fn sighash_anyone_can_pay_leaves_other_inputs_open() {
+        let all = sighash(SigHashMode::All, true);
+        assert!(still_valid(all, other_input_moved));
+        assert!(still_valid(all, input_added));
+        assert!(still_valid(all, |tx| tx.inputs.truncate(1)));
+        assert!(!still_valid(all, |tx| tx.outputs[0].value += 1));
+        // the signed input itself is still covered
+        assert!(!still_valid(all, |tx| tx.inputs[0].sequence = Sequence::Blocks(1)));
+        assert!(!still_valid(all, |tx| tx.inputs[0].previous_output.vout += 1));
+    }
+
+    #[test]
+    fn sighash_none_leaves_outputs_open() {
+        let none = sighash(SigHashMode::None, false);
+        assert!(still_valid(none, |tx| tx.outputs[0].value += 1));
+        assert!(still_valid(none, |tx| tx.outputs.clear()));
+        assert!(!still_valid(none, other_input_moved));
+        let none = sighash(SigHashMode::None, true);
+        assert!(still_valid(none, |tx| tx.outputs[0].value += 1));
+        assert!(still_valid(none, other_input_moved));
+    }
+
+    #[test]
+    fn sighash_single_commits_to_the_matching_output() {
+        let single = sighash(SigHashMode::Single, false);
+        assert!(!still_valid(single, |tx| tx.outputs[0].value += 1));
+        assert!(still_valid(single, |tx| tx.outputs[1].value += 1));
+        assert!(still_valid(single, |tx| tx.outputs.truncate(1)));
+        assert!(!still_valid(single, other_input_moved));
+        let single = sighash(SigHashMode::Single, true);
+        assert!(still_valid(single, other_input_moved));
+        assert!(!still_valid(single, |tx| tx.outputs[0].value += 1));
+    }
+
+    #[test]
+    fn sighash_single_needs_a_matching_output() {
+        let (mut transaction, spent_output, mut key) = two_in_two_out();
+        let single = sighash(SigHashMode::Single, false);
+        assert!(transaction.signature_hash(1, &spent_output, single).is_ok());
+        transaction.outputs.truncate(1);
+        assert!(matches!(
+            transaction.sign_input(1, &spent_output, single, &mut key),
+            Err(BtcError::InvalidTransactionInput)
+        ));
+        transaction.outputs.clear();
+        assert!(
+            transaction
+                .signature_hash(0, &spent_output, single)
+                .is_err()
+        );
+        // an input that does not exist has no hash under any type
+        assert!(
+            transaction
+                .signature_hash(2, &spent_output, SigHashType::ALL)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn sighash_types_round_trip_through_their_byte() {
+        for mode in [SigHashMode::All, SigHashMode::None, SigHashMode::Single] {
+            for anyone_can_pay in [false, true] {
+                let sighash_type = sighash(mode, anyone_can_pay);
+                assert_eq!(
+                    SigHashType::from_byte(sighash_type.to_byte()),
+                    Some(sighash_type)
+                );
+            }
+        }
+        assert_eq!(SigHashType::ALL.to_byte(), 0x01);
+        assert_eq!(sighash(SigHashMode::Single, true).to_byte(), 0x83);
+        for byte in [0x00, 0x04, 0x80, 0xff] {
+            assert_eq!(SigHashType::from_byte(byte), None);
+        }
+    }
+
+    fn next_block(height: u64, prev_time: DateTime<Utc>) -> NextBlock {
+        NextBlock { height, prev_time }
+    }
+
+    // an output confirmed at height 10, at the genesis time of regtest
+    fn confirmed_utxo(coinbase: bool) -> Utxo {
+        Utxo {
+            output: TransactionOutput {
+                value: 1000,
+                unique_id: Uuid::new_v4(),
+                script_pubkey: Script::default(),
+            },
+            height: 10,
+            time: ChainParams::regtest().genesis_timestamp,
+            coinbase,
+        }
+    }
+
+    // a transaction spending `outpoint` with the given locks
+    fn locked_spend(outpoint: OutPoint, lock_time: LockTime, sequence: Sequence) -> Transaction {
+        let input = TransactionInput {
+            previous_output: outpoint,
+            script_sig: Script::default(),
+            sequence,
+        };
+        let mut transaction = Transaction::new(vec![input], vec![]);
+        transaction.lock_time = lock_time;
+        transaction
+    }
+
+    // whether a signed spend of `script_pubkey`, locked to `key`, with
+    // the given locks gets past the script
+    fn script_accepts(
+        script_pubkey: Script,
+        mut key: PrivateKey,
+        lock_time: LockTime,
+        sequence: Sequence,
+    ) -> bool {
+        let spent_output = TransactionOutput {
+            value: 1000,
+            unique_id: Uuid::new_v4(),
+            script_pubkey,
+        };
+        let outpoint = OutPoint::new(Hash::hash(&Uuid::new_v4()), 0);
+        let mut transaction = locked_spend(outpoint, lock_time, sequence);
+        let signature = transaction
+            .sign_input(0, &spent_output, SigHashType::ALL, &mut key)
+            .unwrap();
+        transaction.inputs[0].script_sig = Script::unlock_with_signatures(vec![signature]);
+        let context = ScriptContext {
+            transaction: &transaction,
+            input_index: 0,
+            spent_output: &spent_output,
+        };
+        verify_script(
+            &transaction.inputs[0].script_sig,
+            &spent_output.script_pubkey,
+            &context,
+        )
+        .is_ok()
+    }
+
+    #[test]
+    fn lock_time_by_height_or_by_time() {
+        let now = ChainParams::regtest().genesis_timestamp;
+        let by_height = LockTime::Height(100);
+        assert!(!by_height.is_satisfied(&next_block(99, now + Duration::days(365))));
+        assert!(by_height.is_satisfied(&next_block(100, now)));
+
+        // measured against the previous block, not the new one
+        let by_time = LockTime::Time(now);
+        assert!(!by_time.is_satisfied(&next_block(1_000_000, now - Duration::seconds(1))));
+        assert!(by_time.is_satisfied(&next_block(0, now)));
+        assert!(LockTime::None.is_satisfied(&next_block(0, now)));
+
+        // script numbers only compare heights with heights and times with times
+        let threshold = LOCK_TIME_THRESHOLD;
+        assert!(by_height.reaches(100));
+        assert!(!by_height.reaches(101));
+        assert!(!by_height.reaches(threshold));
+        assert!(by_time.reaches(now.timestamp()));
+        assert!(!by_time.reaches(now.timestamp() + 1));
+        assert!(!by_time.reaches(100));
+        assert!(!LockTime::None.reaches(0));
+    }
+
+    #[test]
+    fn final_inputs_disable_the_lock_time() {
+        let params = ChainParams::regtest();
+        let outpoint = OutPoint::new(Hash::zero(), 0);
+        let utxos = HashMap::from([(outpoint, confirmed_utxo(false))]);
+        let next = next_block(11, params.genesis_timestamp);
+
+        let transaction = locked_spend(outpoint, LockTime::Height(12), Sequence::Final);
+        assert!(!transaction.is_lock_time_enabled());
+        assert!(transaction.check_locks(&utxos, &next, &params).is_ok());
+
+        let transaction = locked_spend(outpoint, LockTime::Height(12), Sequence::Blocks(0));
+        assert!(transaction.is_lock_time_enabled());
+        assert!(matches!(
+            transaction.check_locks(&utxos, &next, &params),
+            Err(BtcError::TransactionLocked)
+        ));
+        let next = next_block(12, params.genesis_timestamp);
+        assert!(transaction.check_locks(&utxos, &next, &params).is_ok());
+    }
+
+    #[test]
+    fn builder_enforces_the_lock_time_it_sets() {
+        let key = PrivateKey::new_key();
+        let output = TransactionOutput {
+            value: 1000,
+            unique_id: Uuid::new_v4(),
+            script_pubkey: Script::p2pkh(&key.public_key()),
+        };
+        let transaction = TransactionBuilder::new()
+            .add_utxos([(OutPoint::new(Hash::zero(), 0), output)])
+            .add_key(key.clone())
+            .add_recipient(key.public_key(), 500)
+            .lock_time(LockTime::Height(12))
+            .build()
+            .unwrap();
+        assert!(transaction.is_lock_time_enabled());
+        assert_eq!(transaction.inputs[0].sequence, Sequence::Blocks(0));
+    }
+
+    #[test]
+    fn relative_locks_at_the_boundary() {
+        let utxo = confirmed_utxo(false);
+        let at = |blocks: i64, seconds: i64| {
+            next_block(
+                (utxo.height as i64 + blocks) as u64,
+                utxo.time + Duration::seconds(seconds),
+            )
+        };
+        assert!(!Sequence::Blocks(5).is_satisfied(&utxo, &at(4, 1_000_000)));
+        assert!(Sequence::Blocks(5).is_satisfied(&utxo, &at(5, 0)));
+        assert!(!Sequence::Seconds(60).is_satisfied(&utxo, &at(1_000, 59)));
+        assert!(Sequence::Seconds(60).is_satisfied(&utxo, &at(0, 60)));
+        assert!(Sequence::Blocks(0).is_satisfied(&utxo, &at(0, 0)));
+
+        assert!(Sequence::Blocks(5).reaches(5));
+        assert!(!Sequence::Blocks(5).reaches(6));
+        assert!(!Sequence::Blocks(5).reaches(Sequence::Seconds(5).to_number()));
+        assert!(Sequence::Seconds(60).reaches(Sequence::Seconds(60).to_number()));
+        assert!(!Sequence::Seconds(60).reaches(Sequence::Seconds(61).to_number()));
+        assert!(!Sequence::Seconds(60).reaches(5));
+        assert!(!Sequence::Final.reaches(0));
+    }
+
+    #[test]
+    fn check_lock_time_verify_at_the_boundary() {
+        let key = PrivateKey::new_key();
+        let script = Script::timelock(LockTime::Height(100), &key.public_key());
+        let spend =
+            |lock_time, sequence| script_accepts(script.clone(), key.clone(), lock_time, sequence);
+        let non_final = Sequence::Blocks(0);
+        assert!(spend(LockTime::Height(100), non_final));
+        assert!(spend(LockTime::Height(101), non_final));
+        assert!(!spend(LockTime::Height(99), non_final));
+        assert!(!spend(LockTime::None, non_final));
+        // a time can not stand in for a height
+        assert!(!spend(LockTime::Time(Utc::now()), non_final));
+        // nor can a lock time that is switched off
+        assert!(!spend(LockTime::Height(100), Sequence::Final));
+    }
+
+    #[test]
+    fn check_sequence_verify_at_the_boundary() {
+        let key = PrivateKey::new_key();
+        let script = Script::relative_timelock(Sequence::Blocks(5), &key.public_key());
+        let spend =
+            |sequence| script_accepts(script.clone(), key.clone(), LockTime::None, sequence);
+        assert!(spend(Sequence::Blocks(5)));
+        assert!(spend(Sequence::Blocks(6)));
+        assert!(!spend(Sequence::Blocks(4)));
+        assert!(!spend(Sequence::Seconds(5)));
+        assert!(!spend(Sequence::Final));
+    }
+
+    // mine until the next block is at `height`
+    fn mine_to(blockchain: &mut BlockChain, key: &PrivateKey, height: u64) {
+        while blockchain.next_block().height < height {
+            mine_block(blockchain, key);
+        }
+    }
+
+    #[test]
+    fn coinbase_is_spendable_from_maturity_on() {
+        let key = PrivateKey::new_key();
+        let mut blockchain = BlockChain::new(ChainParams::regtest());
+        let maturity = blockchain.params().coinbase_maturity;
+        let first = mine_block(&mut blockchain, &key);
+        let outpoint = coinbase_outpoint(&first);
+        assert!(!confirmed_utxo(true).is_mature(10 + maturity - 1, blockchain.params()));
+        assert!(confirmed_utxo(true).is_mature(10 + maturity, blockchain.params()));
+        assert!(confirmed_utxo(false).is_mature(10, blockchain.params()));
+
+        mine_to(&mut blockchain, &key, 1 + maturity - 1);
+        let transaction = spend(&blockchain, &key, outpoint);
+        assert!(matches!(
+            blockchain.add_to_mempool(transaction.clone()),
+            Err(BtcError::ImmatureCoinbase)
+        ));
+        // nor can a block include it yet
+        let mut block = blockchain.create_block_template(key.public_key());
+        block.transactions.push(transaction.clone());
+        assert!(matches!(
+            blockchain.add_block(solve(block)),
+            Err(BtcError::ImmatureCoinbase)
+        ));
+
+        mine_block(&mut blockchain, &key);
+        assert_eq!(blockchain.next_block().height, 1 + maturity);
+        blockchain.add_to_mempool(transaction.clone()).unwrap();
+        let block = mine_block(&mut blockchain, &key);
+        assert_eq!(block.transactions[1].hash(), transaction.hash());
+    }
+
+    #[test]
+    fn spend_that_turns_immature_leaves_the_mempool() {
+        let key = PrivateKey::new_key();
+        let mut blockchain = BlockChain::new(ChainParams::regtest());
+        let maturity = blockchain.params().coinbase_maturity;
+        let first = mine_block(&mut blockchain, &key);
+        mine_to(&mut blockchain, &key, 1 + maturity);
+        let transaction = spend(&blockchain, &key, coinbase_outpoint(&first));
+        blockchain.add_to_mempool(transaction.clone()).unwrap();
+
+        // the coinbase is still there, one block too young
+        blockchain.disconnect_tip().unwrap();
+        assert!(blockchain.utxos().contains_key(&coinbase_outpoint(&first)));
+        assert!(!blockchain.mempool().contains(&transaction.hash()));
+    }
+
+    #[test]
+    fn spend_of_a_coinbase_that_was_reorganized_away_leaves_the_mempool() {
+        let key = PrivateKey::new_key();
+        let mut blockchain = BlockChain::new(ChainParams::regtest());
+        let mut other = BlockChain::new(ChainParams::regtest());
+        let maturity = blockchain.params().coinbase_maturity;
+        let first = mine_block(&mut blockchain, &key);
+        mine_to(&mut blockchain, &key, 1 + maturity);
+        let transaction = spend(&blockchain, &key, coinbase_outpoint(&first));
+        blockchain.add_to_mempool(transaction.clone()).unwrap();
+
+        // a longer branch without the coinbase
+        mine_to(&mut other, &key, blockchain.next_block().height + 1);
+        let mut last = None;
+        for block in other.blocks().skip(1) {
+            last = Some(blockchain.add_block(block.clone()).unwrap());
+        }
+        assert!(matches!(last, Some(BlockStatus::Reorganized { .. })));
+        assert!(!blockchain.utxos().contains_key(&coinbase_outpoint(&first)));
+        assert!(!blockchain.mempool().contains(&transaction.hash()));
+    }
+}
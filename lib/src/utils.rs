@@ -1,27 +1,345 @@
+use crate::U256;
 use crate::sha256::Hash;
-use crate::types::Transaction;
+use crate::types::{Block, Transaction};
 use serde::{Deserialize, Serialize};
+use std::fmt;
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
-pub struct MerkleRoot;
+pub struct MerkleRoot(Hash);
 
 impl MerkleRoot {
     pub fn calculate(transactions: &[Transaction]) -> MerkleRoot {
-        let mut layer: Vec<Hash> = vec![];
-        for transaction in transactions {
-            layer.push(Hash::hash(transaction));
-        }
-        while layer.len() > 1 {
-            let mut new_layer = vec![];
-            for pair in layer.chunks(2) {
-                let left = pair[0];
-                // if there is no right, use the left hash again
-                let right = pair.get(1).unwrap_or(&pair[0]);
-                new_layer.push(Hash::hash(&[left, *right]));
+        // a block without transactions commits to the zero hash
+        match merkle_layers(transactions).last() {
+            Some(layer) => MerkleRoot(layer[0]),
+            None => MerkleRoot(Hash::zero()),
+        }
+    }
+
+    pub fn hash(&self) -> Hash {
+        self.0
+    }
+
+    pub(crate) fn from_hash(hash: Hash) -> MerkleRoot {
+        MerkleRoot(hash)
+    }
+}
+
+impl fmt::Display for MerkleRoot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A target in Bitcoin's compact "bits" form: the top byte is the size of
+/// the target in bytes, the other three are its most significant bytes.
+/// Only about 23 bits of precision survive, which is plenty for a target
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct CompactTarget(pub u32);
+
+impl CompactTarget {
+    /// round `target` down to the closest compact value
+    pub fn from_target(target: U256) -> CompactTarget {
+        let mut size = target.bits().div_ceil(8) as u32;
+        let mut mantissa = if size <= 3 {
+            target.low_u32() << (8 * (3 - size))
+        } else {
+            (target >> (8 * (size - 3) as usize)).low_u32()
+        };
+        // the top mantissa bit is a sign bit, targets are never negative
+        if mantissa & 0x0080_0000 != 0 {
+            mantissa >>= 8;
+            size += 1;
+        }
+        CompactTarget(size << 24 | mantissa)
+    }
+
+    /// the full target. negative and overflowing values come out as zero,
+    /// a target no hash can meet
+    pub fn to_target(self) -> U256 {
+        let size = (self.0 >> 24) as usize;
+        let mantissa = self.0 & 0x007f_ffff;
+        if mantissa == 0 || self.0 & 0x0080_0000 != 0 {
+            return U256::zero();
+        }
+        if size <= 3 {
+            return U256::from(mantissa >> (8 * (3 - size)));
+        }
+        let shift = 8 * (size - 3);
+        if shift + (32 - mantissa.leading_zeros() as usize) > 256 {
+            return U256::zero();
+        }
+        U256::from(mantissa) << shift
+    }
+}
+
+impl fmt::Display for CompactTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:08x}", self.0)
+    }
+}
+
+/// Proof that a transaction is part of a block, checkable against the
+/// block header's merkle root without having the other transactions
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct MerkleProof {
+    /// Position of the transaction in the block
+    pub index: u64,
+    /// Number of transactions in the block
+    pub leaf_count: u64,
+    /// Sibling hashes from the transaction up to the root
+    pub siblings: Vec<Hash>,
+}
+
+impl MerkleProof {
+    /// build a proof for `tx_hash`, None if the block does not contain it
+    pub fn generate(block: &Block, tx_hash: &Hash) -> Option<MerkleProof> {
+        let layers = merkle_layers(&block.transactions);
+        let mut index = layers.first()?.iter().position(|hash| hash == tx_hash)?;
+        let proof_index = index as u64;
+        let mut siblings = vec![];
+        // every layer except the root contributes one sibling
+        for layer in &layers[..layers.len() - 1] {
+            let sibling = if index.is_multiple_of(2) {
+                // if there is no right, the left hash was used again
+                layer.get(index + 1).unwrap_or(&layer[index])
+            } else {
+                &layer[index - 1]
+            };
+            siblings.push(*sibling);
+            index /= 2;
+        }
+        Some(MerkleProof {
+            index: proof_index,
+            leaf_count: layers[0].len() as u64,
+            siblings,
+        })
+    }
+
+    pub fn verify(&self, tx_hash: &Hash, root: &MerkleRoot) -> bool {
+        // an index past the last transaction would let the proof of the
+        // duplicated last leaf pass for a transaction that does not exist
+        if self.index >= self.leaf_count {
+            return false;
+        }
+        let mut hash = *tx_hash;
+        let mut index = self.index;
+        let mut width = self.leaf_count;
+        for sibling in &self.siblings {
+            if width == 1 {
+                return false;
+            }
+            // only the last hash of a layer with an odd width is paired
+            // with itself, anywhere else an equal sibling is a forgery
+            let duplicated = index.is_multiple_of(2) && index + 1 == width;
+            if (*sibling == hash) != duplicated {
+                return false;
+            }
+            hash = if index.is_multiple_of(2) {
+                Hash::hash(&[hash, *sibling])
+            } else {
+                Hash::hash(&[*sibling, hash])
+            };
+            index /= 2;
+            width = width.div_ceil(2);
+        }
+        width == 1 && hash == root.hash()
+    }
+}
+
+// every layer of the merkle tree, from the transaction hashes up to
+// the single root hash. empty if there are no transactions
+fn merkle_layers(transactions: &[Transaction]) -> Vec<Vec<Hash>> {
+    let mut layer: Vec<Hash> = vec![];
+    for transaction in transactions {
+        layer.push(Hash::hash(transaction));
+    }
+    if layer.is_empty() {
+        return vec![];
+    }
+    let mut layers = vec![layer.clone()];
+    while layer.len() > 1 {
+        let mut new_layer = vec![];
+        for pair in layer.chunks(2) {
+            let left = pair[0];
+            // if there is no right, use the left hash again
+            let right = pair.get(1).unwrap_or(&pair[0]);
+            new_layer.push(Hash::hash(&[left, *right]));
+        }
+        layer = new_layer;
+        layers.push(layer.clone());
+    }
+    layers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script::Script;
+    use crate::types::{BlockHeader, TransactionOutput};
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    fn block_with(leaves: u64) -> Block {
+        let transactions: Vec<Transaction> = (0..leaves)
+            .map(|value| {
+                Transaction::new(
+                    vec![],
+                    vec![TransactionOutput {
+                        value,
+                        unique_id: Uuid::nil(),
+                        script_pubkey: Script(vec![]),
+                    }],
+                )
+            })
+            .collect();
+        let header = BlockHeader::new(
+            Utc::now(),
+            0,
+            Hash::zero(),
+            MerkleRoot::calculate(&transactions),
+            CompactTarget::default(),
+        );
+        Block::new(header, transactions)
+    }
+
+    #[test]
+    fn proofs_round_trip() {
+        for leaves in [1, 2, 3, 5] {
+            let block = block_with(leaves);
+            for transaction in &block.transactions {
+                let tx_hash = transaction.hash();
+                let proof = MerkleProof::generate(&block, &tx_hash).unwrap();
+                assert!(proof.verify(&tx_hash, &block.header.markle_root));
             }
-            layer = new_layer;
         }
-        layer[0];
-        MerkleRoot
+    }
+
+    #[test]
+    fn unknown_transaction_has_no_proof() {
+        let block = block_with(3);
+        let other = block_with(5).transactions[4].hash();
+        assert_eq!(MerkleProof::generate(&block, &other), None);
+    }
+
+    #[test]
+    fn duplicated_last_leaf_does_not_verify_past_the_end() {
+        let block = block_with(3);
+        let root = block.header.markle_root;
+        let tx_hash = block.transactions[2].hash();
+        let proof = MerkleProof::generate(&block, &tx_hash).unwrap();
+        // [a, b, c, c] has the same root as [a, b, c]
+        let past_the_end = MerkleProof {
+            index: 3,
+            ..proof.clone()
+        };
+        assert!(!past_the_end.verify(&tx_hash, &root));
+        let claims_four_leaves = MerkleProof {
+            index: 3,
+            leaf_count: 4,
+            ..proof
+        };
+        assert!(!claims_four_leaves.verify(&tx_hash, &root));
+    }
+
+    #[test]
+    fn tampered_proofs_do_not_verify() {
+        let block = block_with(5);
+        let root = block.header.markle_root;
+        let tx_hash = block.transactions[1].hash();
+        let proof = MerkleProof::generate(&block, &tx_hash).unwrap();
+
+        let wrong_index = MerkleProof {
+            index: 0,
+            ..proof.clone()
+        };
+        assert!(!wrong_index.verify(&tx_hash, &root));
+
+        let mut wrong_sibling = proof.clone();
+        wrong_sibling.siblings[1] = Hash::zero();
+        assert!(!wrong_sibling.verify(&tx_hash, &root));
+
+        let mut extra_sibling = proof.clone();
+        extra_sibling.siblings.push(root.hash());
+        assert!(!extra_sibling.verify(&tx_hash, &root));
+
+        let mut missing_sibling = proof.clone();
+        missing_sibling.siblings.pop();
+        assert!(!missing_sibling.verify(&tx_hash, &root));
+
+        let wrong_count = MerkleProof {
+            leaf_count: 2,
+            ..proof.clone()
+        };
+        assert!(!wrong_count.verify(&tx_hash, &root));
+
+        let other = block.transactions[2].hash();
+        assert!(!proof.verify(&other, &root));
+        assert!(!proof.verify(&tx_hash, &MerkleRoot::from_hash(Hash::zero())));
+    }
+
+    fn header_with(bits: CompactTarget) -> BlockHeader {
+        BlockHeader::new(
+            Utc::now(),
+            0,
+            Hash::zero(),
+            MerkleRoot::calculate(&[]),
+            bits,
+        )
+    }
+
+    #[test]
+    fn compact_targets_round_trip() {
+        let cases = [
+            (0x1d00_ffff, U256::from(0xffff) << 208),
+            (0x1b04_04cb, U256::from(0x0404cb) << 192),
+        ];
+        for (bits, target) in cases {
+            assert_eq!(CompactTarget(bits).to_target(), target);
+            assert_eq!(CompactTarget::from_target(target), CompactTarget(bits));
+        }
+    }
+
+    #[test]
+    fn mantissa_never_has_the_sign_bit_set() {
+        // 0x80 would be 0x01800000, a negative number
+        let bits = CompactTarget::from_target(U256::from(0x80));
+        assert_eq!(bits, CompactTarget(0x0200_8000));
+        assert_eq!(bits.to_target(), U256::from(0x80));
+        assert_eq!(
+            CompactTarget::from_target(U256::from(0x80) << 200),
+            CompactTarget(0x1b00_8000)
+        );
+        assert!(CompactTarget(0x0180_0000).to_target().is_zero());
+        assert!(CompactTarget(0x1d80_ffff).to_target().is_zero());
+    }
+
+    #[test]
+    fn zero_and_overflowing_exponents() {
+        assert_eq!(CompactTarget::from_target(U256::zero()), CompactTarget(0));
+        assert!(CompactTarget(0).to_target().is_zero());
+        // the whole mantissa is shifted out
+        assert!(CompactTarget(0x0012_3456).to_target().is_zero());
+        assert_eq!(CompactTarget(0x0112_3456).to_target(), U256::from(0x12));
+        // the largest target that still fits
+        assert_eq!(
+            CompactTarget(0x2100_ffff).to_target(),
+            U256::from(0xffff) << 240
+        );
+        assert!(CompactTarget(0x2101_0000).to_target().is_zero());
+        assert!(CompactTarget(0xff12_3456).to_target().is_zero());
+    }
+
+    #[test]
+    fn work_is_two_to_the_256_over_target_plus_one() {
+        let target = CompactTarget(0x1d00_ffff).to_target();
+        let work = header_with(CompactTarget(0x1d00_ffff)).work();
+        assert_eq!(work, (!target / (target + U256::one())) + U256::one());
+        // the work of the bitcoin genesis block
+        assert_eq!(work, U256::from(0x1_0001_0001u64));
+        // half the target, twice the work
+        let harder = CompactTarget::from_target(target / U256::from(2));
+        assert_eq!(header_with(harder).work(), work * U256::from(2));
+        assert!(header_with(CompactTarget(0)).work().is_zero());
     }
 }
@@ -1,3 +1,148 @@
+mod mining;
+
+use lib::crypto::PrivateKey;
+use lib::encode::{Decodable, Encodable};
+use lib::params::{ChainParams, Network};
+use lib::rpc::RpcClient;
+use lib::sha256::Hash;
+use lib::types::Block;
+use mining::Miner;
+use serde_json::{Value, json};
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// the node is expected on its network's default rpc port
+const DEFAULT_RPC_HOST: &str = "127.0.0.1";
+// how often the hash rate is printed
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+// how often the node is asked whether the tip moved
+const TIP_POLL_INTERVAL: Duration = Duration::from_millis(500);
+// how long to wait before asking a node that failed to answer again
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: miner [--network <mainnet|testnet|regtest>] [--rpc <host:port>] <key-file> [threads]"
+    );
+    std::process::exit(1);
+}
+
 fn main() {
-    println!("Hello, world!");
+    let mut network = Network::Mainnet;
+    let mut rpc_addr = None;
+    let mut positional = vec![];
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--network" => {
+                network = args
+                    .next()
+                    .and_then(|network| network.parse().ok())
+                    .unwrap_or_else(|| usage())
+            }
+            "--rpc" => rpc_addr = Some(args.next().unwrap_or_else(|| usage())),
+            _ => positional.push(arg),
+        }
+    }
+    let mut positional = positional.into_iter();
+    let Some(key_file) = positional.next() else {
+        usage()
+    };
+    let threads = positional
+        .next()
+        .and_then(|threads| threads.parse().ok())
+        .unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(|threads| threads.get())
+                .unwrap_or(1)
+        });
+    let rpc_addr = rpc_addr.unwrap_or_else(|| {
+        let port = ChainParams::for_network(network).default_rpc_port;
+        format!("{}:{}", DEFAULT_RPC_HOST, port)
+    });
+
+    let key = load_or_create_key(Path::new(&key_file));
+    let rpc = RpcClient::new(rpc_addr.clone());
+    let miner = Miner::new(threads);
+    println!("mining on {} threads for the node at {}", threads, rpc_addr);
+
+    let mut last_report = Instant::now();
+    let mut last_hashes = 0;
+    loop {
+        // the node picks the transactions and the target, the miner
+        // only looks for a nonce
+        let mut block = match block_template(&rpc, &key) {
+            Ok(block) => block,
+            Err(e) => {
+                eprintln!("failed to get a block template: {}", e);
+                thread::sleep(RETRY_INTERVAL);
+                continue;
+            }
+        };
+        let tip = block.header.prev_block_hash;
+        let job = miner.start(block.header.clone());
+        let solved = loop {
+            if let Some(header) = job.wait(TIP_POLL_INTERVAL) {
+                break Some(header);
+            }
+            if last_report.elapsed() >= REPORT_INTERVAL {
+                let hashes = miner.hashes();
+                println!(
+                    "{:.0} hashes/s",
+                    (hashes - last_hashes) as f64 / last_report.elapsed().as_secs_f64()
+                );
+                last_report = Instant::now();
+                last_hashes = hashes;
+            }
+            // the work is worthless once someone else extended the chain
+            match best_block_hash(&rpc) {
+                Ok(best) if best != tip => break None,
+                Ok(_) => {}
+                Err(e) => eprintln!("failed to get the tip: {}", e),
+            }
+        };
+        job.abort();
+        let Some(header) = solved else {
+            continue;
+        };
+        block.header = header;
+        match rpc.call("submitblock", json!([hex::encode(block.to_bytes())])) {
+            Ok(hash) => println!("mined block {}", hash.as_str().unwrap_or_default()),
+            Err(e) => eprintln!("mined block was rejected: {}", e),
+        }
+    }
+}
+
+fn block_template(rpc: &RpcClient, key: &PrivateKey) -> io::Result<Block> {
+    let public_key = hex::encode(key.public_key().to_bytes());
+    let template = rpc.call("getblocktemplate", json!([public_key]))?;
+    decode_hex(&template)
+}
+
+fn best_block_hash(rpc: &RpcClient) -> io::Result<Hash> {
+    rpc.call("getbestblockhash", json!([]))?
+        .as_str()
+        .and_then(|hash| hash.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad block hash"))
+}
+
+fn decode_hex<T: Decodable>(value: &Value) -> io::Result<T> {
+    value
+        .as_str()
+        .and_then(|hex| hex::decode(hex).ok())
+        .and_then(|bytes| T::from_bytes(&bytes).ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "expected canonical hex"))
+}
+
+fn load_or_create_key(path: &Path) -> PrivateKey {
+    if let Ok(file) = File::open(path) {
+        return ciborium::from_reader(file).expect("failed to read miner key");
+    }
+    let key = PrivateKey::new_key();
+    let file = File::create(path).expect("failed to create miner key");
+    ciborium::into_writer(&key, file).expect("failed to write miner key");
+    key
 }
@@ -0,0 +1,98 @@
+use lib::clock::SystemClock;
+use lib::types::BlockHeader;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// nonces tried between two checks of the abort flag
+const BATCH_SIZE: usize = 10_000;
+
+/// Mines block headers on a fixed number of worker threads
+pub struct Miner {
+    threads: usize,
+    hashes: Arc<AtomicU64>,
+}
+
+/// A header being mined, dropped or aborted as soon as it is stale
+pub struct MiningJob {
+    abort: Arc<AtomicBool>,
+    solution: Receiver<BlockHeader>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Miner {
+    pub fn new(threads: usize) -> Self {
+        Miner {
+            threads: threads.max(1),
+            hashes: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// total number of hashes computed so far, for hash rate reporting
+    pub fn hashes(&self) -> u64 {
+        self.hashes.load(Ordering::Relaxed)
+    }
+
+    /// start mining `header`. every worker gets its own slice of the
+    /// nonce space so no two threads hash the same header
+    pub fn start(&self, header: BlockHeader) -> MiningJob {
+        let abort = Arc::new(AtomicBool::new(false));
+        let (sender, solution) = mpsc::channel();
+        let range = u64::MAX / self.threads as u64;
+        let workers = (0..self.threads)
+            .map(|worker| {
+                let mut header = header.clone();
+                header.nonce = worker as u64 * range;
+                let abort = abort.clone();
+                let hashes = self.hashes.clone();
+                let sender = sender.clone();
+                thread::spawn(move || {
+                    while !abort.load(Ordering::Relaxed) {
+                        if header.mine(BATCH_SIZE, &SystemClock) {
+                            // stop the other workers, one solution is enough
+                            abort.store(true, Ordering::Relaxed);
+                            let _ = sender.send(header);
+                            return;
+                        }
+                        hashes.fetch_add(BATCH_SIZE as u64, Ordering::Relaxed);
+                    }
+                })
+            })
+            .collect();
+        MiningJob {
+            abort,
+            solution,
+            workers,
+        }
+    }
+}
+
+impl MiningJob {
+    /// wait up to `timeout` for a solved header
+    pub fn wait(&self, timeout: Duration) -> Option<BlockHeader> {
+        match self.solution.recv_timeout(timeout) {
+            Ok(header) => Some(header),
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => None,
+        }
+    }
+
+    /// stop all workers, e.g. because a new tip arrived
+    pub fn abort(mut self) {
+        self.stop();
+    }
+
+    fn stop(&mut self) {
+        self.abort.store(true, Ordering::Relaxed);
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Drop for MiningJob {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
@@ -1,3 +1,66 @@
+mod network;
+mod node;
+mod rpc;
+mod sync;
+
+use lib::params::{ChainParams, Network};
+use lib::types::BlockChain;
+use node::Node;
+
+// each network keeps its chain in a directory of its own under this one
+const DEFAULT_DATA_DIR: &str = "./data";
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: node [--network <mainnet|testnet|regtest>] [--port <port>] [--rpc-port <port>] [--data-dir <dir>] [--peer <host:port>]..."
+    );
+    std::process::exit(1);
+}
+
 fn main() {
-    println!("Hello, world!");
+    let mut network = Network::Mainnet;
+    let mut port = None;
+    let mut rpc_port = None;
+    let mut data_dir = None;
+    let mut peers = vec![];
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let Some(value) = args.next() else { usage() };
+        match arg.as_str() {
+            "--network" => network = value.parse().unwrap_or_else(|_| usage()),
+            "--port" => port = Some(value.parse().unwrap_or_else(|_| usage())),
+            "--rpc-port" => rpc_port = Some(value.parse().unwrap_or_else(|_| usage())),
+            "--data-dir" => data_dir = Some(value),
+            "--peer" => peers.push(value),
+            _ => usage(),
+        }
+    }
+    let params = ChainParams::for_network(network);
+    let port = port.unwrap_or(params.default_port);
+    let rpc_port = rpc_port.unwrap_or(params.default_rpc_port);
+    let data_dir = data_dir.unwrap_or_else(|| format!("{}/{}", DEFAULT_DATA_DIR, network));
+
+    let blockchain = BlockChain::open(&data_dir, params).expect("failed to open the blockchain");
+    println!(
+        "loaded {} blocks of {} from {}",
+        blockchain.block_height(),
+        network,
+        data_dir
+    );
+    let node = Node::new(blockchain, port);
+    for peer in peers {
+        if let Err(e) = node.connect(&peer) {
+            eprintln!("failed to connect to {}: {}", peer, e);
+        }
+    }
+    if let Err(e) = node.serve_rpc(rpc_port) {
+        eprintln!("failed to serve json-rpc on port {}: {}", rpc_port, e);
+        std::process::exit(1);
+    }
+    node.keep_alive();
+    node.watch_downloads();
+    if let Err(e) = node.listen() {
+        eprintln!("failed to listen on port {}: {}", port, e);
+        std::process::exit(1);
+    }
 }
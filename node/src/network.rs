@@ -0,0 +1,198 @@
+use lib::encode::{Decodable, Encodable};
+use lib::error::{BtcError, Result};
+use lib::sha256::Hash;
+use lib::types::{Block, BlockHeader, Transaction};
+use std::io::{self, Read, Write};
+
+pub const PROTOCOL_VERSION: u32 = 4;
+// largest payload we are willing to read
+pub const MAX_MESSAGE_SIZE: usize = 32 * 1024 * 1024;
+// most entries sent in a single inv
+pub const MAX_INV_SIZE: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Inventory {
+    Block(Hash),
+    Transaction(Hash),
+}
+
+#[derive(Debug, Clone)]
+pub enum Message {
+    /// First message on every connection
+    Version {
+        version: u32,
+        /// Peers with another genesis block are on another chain
+        genesis: Hash,
+        height: u64,
+        tip: Hash,
+        /// Port the sender accepts connections on
+        listen_port: u16,
+    },
+    /// Acknowledges a version message, the handshake is done
+    Verack,
+    /// Announces blocks or transactions the sender has
+    Inv(Vec<Inventory>),
+    /// Asks for the full blocks or transactions
+    GetData(Vec<Inventory>),
+    /// Asks for the headers after the first known locator hash
+    GetHeaders {
+        locator: Vec<Hash>,
+    },
+    /// Headers following each other, a full batch means there are more
+    Headers(Vec<BlockHeader>),
+    Block(Block),
+    Tx(Transaction),
+    Ping(u64),
+    Pong(u64),
+}
+
+impl Message {
+    /// write one frame: magic, payload length, payload in the canonical
+    /// encoding. The magic is the network's, so garbage and other
+    /// networks are rejected early
+    pub fn send(&self, magic: [u8; 4], stream: &mut impl Write) -> io::Result<()> {
+        let payload = self.to_bytes();
+        let mut frame = Vec::with_capacity(8 + payload.len());
+        frame.extend_from_slice(&magic);
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&payload);
+        stream.write_all(&frame)?;
+        stream.flush()
+    }
+
+    pub fn receive(magic: [u8; 4], stream: &mut impl Read) -> io::Result<Message> {
+        let mut header = [0u8; 8];
+        stream.read_exact(&mut header)?;
+        if header[0..4] != magic {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "bad magic"));
+        }
+        let len = u32::from_be_bytes(header[4..8].try_into().unwrap()) as usize;
+        if len > MAX_MESSAGE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "message too large",
+            ));
+        }
+        let mut payload = vec![0u8; len];
+        stream.read_exact(&mut payload)?;
+        Message::from_bytes(&payload)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+    }
+}
+
+// the first byte of a payload says which message it is
+const VERSION: u8 = 0;
+const VERACK: u8 = 1;
+const INV: u8 = 2;
+const GET_DATA: u8 = 3;
+const GET_HEADERS: u8 = 4;
+const HEADERS: u8 = 5;
+const BLOCK: u8 = 6;
+const TX: u8 = 7;
+const PING: u8 = 8;
+const PONG: u8 = 9;
+
+// inventory types are bitcoin's
+const INV_TRANSACTION: u8 = 1;
+const INV_BLOCK: u8 = 2;
+
+impl Encodable for Inventory {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        let (kind, hash) = match self {
+            Inventory::Transaction(hash) => (INV_TRANSACTION, hash),
+            Inventory::Block(hash) => (INV_BLOCK, hash),
+        };
+        kind.encode(buf);
+        hash.encode(buf);
+    }
+}
+
+impl Decodable for Inventory {
+    fn decode(reader: &mut &[u8]) -> Result<Self> {
+        match u8::decode(reader)? {
+            INV_TRANSACTION => Hash::decode(reader).map(Inventory::Transaction),
+            INV_BLOCK => Hash::decode(reader).map(Inventory::Block),
+            _ => Err(BtcError::InvalidEncoding),
+        }
+    }
+}
+
+impl Encodable for Message {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Message::Version {
+                version,
+                genesis,
+                height,
+                tip,
+                listen_port,
+            } => {
+                VERSION.encode(buf);
+                version.encode(buf);
+                genesis.encode(buf);
+                height.encode(buf);
+                tip.encode(buf);
+                listen_port.encode(buf);
+            }
+            Message::Verack => VERACK.encode(buf),
+            Message::Inv(inventory) => {
+                INV.encode(buf);
+                inventory.encode(buf);
+            }
+            Message::GetData(inventory) => {
+                GET_DATA.encode(buf);
+                inventory.encode(buf);
+            }
+            Message::GetHeaders { locator } => {
+                GET_HEADERS.encode(buf);
+                locator.encode(buf);
+            }
+            Message::Headers(headers) => {
+                HEADERS.encode(buf);
+                headers.encode(buf);
+            }
+            Message::Block(block) => {
+                BLOCK.encode(buf);
+                block.encode(buf);
+            }
+            Message::Tx(transaction) => {
+                TX.encode(buf);
+                transaction.encode(buf);
+            }
+            Message::Ping(nonce) => {
+                PING.encode(buf);
+                nonce.encode(buf);
+            }
+            Message::Pong(nonce) => {
+                PONG.encode(buf);
+                nonce.encode(buf);
+            }
+        }
+    }
+}
+
+impl Decodable for Message {
+    fn decode(reader: &mut &[u8]) -> Result<Self> {
+        Ok(match u8::decode(reader)? {
+            VERSION => Message::Version {
+                version: u32::decode(reader)?,
+                genesis: Hash::decode(reader)?,
+                height: u64::decode(reader)?,
+                tip: Hash::decode(reader)?,
+                listen_port: u16::decode(reader)?,
+            },
+            VERACK => Message::Verack,
+            INV => Message::Inv(Vec::decode(reader)?),
+            GET_DATA => Message::GetData(Vec::decode(reader)?),
+            GET_HEADERS => Message::GetHeaders {
+                locator: Vec::decode(reader)?,
+            },
+            HEADERS => Message::Headers(Vec::decode(reader)?),
+            BLOCK => Message::Block(Block::decode(reader)?),
+            TX => Message::Tx(Transaction::decode(reader)?),
+            PING => Message::Ping(u64::decode(reader)?),
+            PONG => Message::Pong(u64::decode(reader)?),
+            _ => return Err(BtcError::InvalidEncoding),
+        })
+    }
+}
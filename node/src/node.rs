@@ -0,0 +1,548 @@
+use crate::network::{Inventory, MAX_INV_SIZE, Message, PROTOCOL_VERSION};
+use crate::sync::{HeaderSync, MAX_HEADERS, Misbehavior};
+use lib::error::BtcError;
+use lib::sha256::Hash;
+use lib::types::{Block, BlockChain, BlockHeader, BlockStatus, Transaction};
+use std::collections::HashMap;
+use std::io;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// how often idle peers are pinged
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+// how often block downloads are checked for timeouts
+const SYNC_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A connected peer. Reading happens on the peer's own thread,
+/// writing from whichever thread has something to send
+pub struct Peer {
+    pub addr: SocketAddr,
+    /// Magic bytes of the network the node is on
+    magic: [u8; 4],
+    writer: Mutex<TcpStream>,
+    state: Mutex<PeerState>,
+}
+
+#[derive(Default)]
+struct PeerState {
+    /// Number of blocks the peer claims to have
+    height: u64,
+    /// The peer's version message arrived and was accepted
+    handshake_done: bool,
+}
+
+impl Peer {
+    pub fn send(&self, message: &Message) -> io::Result<()> {
+        message.send(self.magic, &mut *self.writer.lock().unwrap())
+    }
+
+    /// close the connection, the reader thread notices and cleans up
+    pub fn disconnect(&self) {
+        let _ = self.writer.lock().unwrap().shutdown(Shutdown::Both);
+    }
+
+    fn height(&self) -> u64 {
+        self.state.lock().unwrap().height
+    }
+
+    fn saw_height(&self, height: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.height = state.height.max(height);
+    }
+}
+
+pub struct Node {
+    blockchain: Mutex<BlockChain>,
+    peers: Mutex<HashMap<SocketAddr, Arc<Peer>>>,
+    // always locked after the blockchain when both are needed
+    sync: Mutex<HeaderSync>,
+    listen_port: u16,
+}
+
+impl Node {
+    pub fn new(blockchain: BlockChain, listen_port: u16) -> Arc<Self> {
+        Arc::new(Node {
+            blockchain: Mutex::new(blockchain),
+            peers: Mutex::new(HashMap::new()),
+            sync: Mutex::new(HeaderSync::default()),
+            listen_port,
+        })
+    }
+
+    pub fn blockchain(&self) -> MutexGuard<'_, BlockChain> {
+        self.blockchain.lock().unwrap()
+    }
+
+    /// accept incoming connections forever
+    pub fn listen(self: &Arc<Self>) -> io::Result<()> {
+        let listener = TcpListener::bind(("0.0.0.0", self.listen_port))?;
+        println!("listening for peers on port {}", self.listen_port);
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = self.add_peer(stream) {
+                        eprintln!("failed to set up peer: {}", e);
+                    }
+                }
+                Err(e) => eprintln!("failed to accept peer: {}", e),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn connect(self: &Arc<Self>, addr: impl ToSocketAddrs) -> io::Result<()> {
+        let stream = TcpStream::connect(addr)?;
+        self.add_peer(stream)
+    }
+
+    /// ping every peer from time to time, dropping the ones that are gone
+    pub fn keep_alive(self: &Arc<Self>) {
+        let node = self.clone();
+        thread::spawn(move || {
+            loop {
+                thread::sleep(PING_INTERVAL);
+                let nonce = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|time| time.as_nanos() as u64)
+                    .unwrap_or_default();
+                node.broadcast(&Message::Ping(nonce), None);
+            }
+        });
+    }
+
+    /// give up on block downloads that take too long, ask someone else
+    /// for them and drop peers that keep stalling
+    pub fn watch_downloads(self: &Arc<Self>) {
+        let node = self.clone();
+        thread::spawn(move || {
+            loop {
+                thread::sleep(SYNC_CHECK_INTERVAL);
+                let mut misbehaving = vec![];
+                {
+                    let mut sync = node.sync.lock().unwrap();
+                    let (stalled, unavailable) = sync.check_timeouts();
+                    misbehaving.extend(stalled);
+                    if unavailable {
+                        // nobody can deliver the blocks, the headers were a lie
+                        println!("blocks of the pending headers are unavailable");
+                        misbehaving.extend(sync.headers_from());
+                        sync.abandon();
+                    }
+                }
+                for addr in misbehaving {
+                    node.disconnect(addr);
+                }
+                node.request_blocks();
+            }
+        });
+    }
+
+    fn add_peer(self: &Arc<Self>, stream: TcpStream) -> io::Result<()> {
+        let addr = stream.peer_addr()?;
+        let magic = self.blockchain().params().magic;
+        let peer = Arc::new(Peer {
+            addr,
+            magic,
+            writer: Mutex::new(stream.try_clone()?),
+            state: Mutex::new(PeerState::default()),
+        });
+        // the version has to go out before anything broadcast to the peers
+        peer.send(&self.version_message())?;
+        self.peers.lock().unwrap().insert(addr, peer.clone());
+        println!("connected to {}", addr);
+
+        let node = self.clone();
+        thread::spawn(move || {
+            let mut reader = stream;
+            loop {
+                let message = match Message::receive(magic, &mut reader) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        println!("disconnected from {}: {}", peer.addr, e);
+                        break;
+                    }
+                };
+                if let Err(e) = node.handle_message(&peer, message) {
+                    println!("disconnected from {}: {}", peer.addr, e);
+                    break;
+                }
+            }
+            node.peers.lock().unwrap().remove(&peer.addr);
+            node.sync.lock().unwrap().peer_disconnected(peer.addr);
+            node.request_blocks();
+        });
+        Ok(())
+    }
+
+    fn version_message(&self) -> Message {
+        let blockchain = self.blockchain();
+        Message::Version {
+            version: PROTOCOL_VERSION,
+            genesis: blockchain.genesis_hash(),
+            height: blockchain.block_height(),
+            tip: blockchain.tip_hash(),
+            listen_port: self.listen_port,
+        }
+    }
+
+    fn get_headers_message(&self) -> Message {
+        let blockchain = self.blockchain();
+        Message::GetHeaders {
+            locator: self.sync.lock().unwrap().locator(&blockchain),
+        }
+    }
+
+    pub fn disconnect(&self, addr: SocketAddr) {
+        if let Some(peer) = self.peers.lock().unwrap().remove(&addr) {
+            println!("disconnecting {}", addr);
+            peer.disconnect();
+        }
+    }
+
+    /// hand out block downloads to every peer that has room for more
+    fn request_blocks(&self) {
+        let peers: Vec<Arc<Peer>> = self.peers.lock().unwrap().values().cloned().collect();
+        for peer in peers {
+            let hashes = self
+                .sync
+                .lock()
+                .unwrap()
+                .next_requests(peer.addr, peer.height());
+            if hashes.is_empty() {
+                continue;
+            }
+            let request = Message::GetData(hashes.into_iter().map(Inventory::Block).collect());
+            if peer.send(&request).is_err() {
+                self.disconnect(peer.addr);
+            }
+        }
+    }
+
+    /// send to every peer except `except`, peers that fail to
+    /// receive are dropped
+    pub fn broadcast(&self, message: &Message, except: Option<SocketAddr>) {
+        let peers: Vec<Arc<Peer>> = self.peers.lock().unwrap().values().cloned().collect();
+        for peer in peers {
+            if Some(peer.addr) == except {
+                continue;
+            }
+            if peer.send(message).is_err() {
+                self.peers.lock().unwrap().remove(&peer.addr);
+            }
+        }
+    }
+
+    /// validate a block from outside the network, e.g. a miner,
+    /// and announce it to every peer
+    pub fn submit_block(&self, block: Block) -> lib::error::Result<Hash> {
+        let hash = block.hash();
+        let mut blockchain = self.blockchain();
+        let status = blockchain.add_block(block)?;
+        log_status(&hash, status);
+        blockchain.flush()?;
+        println!(
+            "accepted submitted block {}, height {}",
+            hash,
+            blockchain.block_height()
+        );
+        drop(blockchain);
+        self.broadcast(&Message::Inv(vec![Inventory::Block(hash)]), None);
+        Ok(hash)
+    }
+
+    /// add a transaction to the mempool and announce it to every peer
+    pub fn submit_transaction(&self, transaction: Transaction) -> lib::error::Result<Hash> {
+        let hash = transaction.hash();
+        self.blockchain().add_to_mempool(transaction)?;
+        self.broadcast(&Message::Inv(vec![Inventory::Transaction(hash)]), None);
+        Ok(hash)
+    }
+
+    fn handle_message(&self, peer: &Peer, message: Message) -> io::Result<()> {
+        let handshake_done = peer.state.lock().unwrap().handshake_done;
+        check_handshake(&message, handshake_done).map_err(misbehaving)?;
+        match message {
+            Message::Version {
+                version,
+                genesis,
+                height,
+                ..
+            } => {
+                if version != PROTOCOL_VERSION {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "unsupported protocol version",
+                    ));
+                }
+                if genesis != self.blockchain().genesis_hash() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "different genesis block",
+                    ));
+                }
+                peer.state.lock().unwrap().handshake_done = true;
+                peer.send(&Message::Verack)?;
+                peer.saw_height(height);
+                // the peer is ahead of us, fetch its headers first
+                if height > self.blockchain().block_height() {
+                    peer.send(&self.get_headers_message())?;
+                }
+            }
+            Message::Verack => {}
+            Message::Inv(inventory) => {
+                if inventory.len() > MAX_INV_SIZE {
+                    return Err(misbehaving(Misbehavior("inv too large")));
+                }
+                let blockchain = self.blockchain();
+                let mut new_blocks = false;
+                let mut missing = vec![];
+                for item in inventory {
+                    match item {
+                        Inventory::Block(hash) => {
+                            new_blocks |= blockchain.get_block(&hash).is_none();
+                        }
+                        Inventory::Transaction(hash) => {
+                            if !blockchain.mempool().contains(&hash) {
+                                missing.push(item);
+                            }
+                        }
+                    }
+                }
+                drop(blockchain);
+                // blocks are always fetched through their headers
+                if new_blocks {
+                    peer.send(&self.get_headers_message())?;
+                }
+                if !missing.is_empty() {
+                    peer.send(&Message::GetData(missing))?;
+                }
+            }
+            Message::GetData(inventory) => {
+                for item in inventory {
+                    let reply = {
+                        let blockchain = self.blockchain();
+                        match item {
+                            Inventory::Block(hash) => {
+                                blockchain.get_block(&hash).cloned().map(Message::Block)
+                            }
+                            Inventory::Transaction(hash) => blockchain
+                                .mempool()
+                                .get(&hash)
+                                .map(|entry| Message::Tx(entry.transaction.clone())),
+                        }
+                    };
+                    if let Some(reply) = reply {
+                        peer.send(&reply)?;
+                    }
+                }
+            }
+            Message::GetHeaders { locator } => {
+                let blockchain = self.blockchain();
+                let headers = blockchain
+                    .block_hashes_after(&locator, MAX_HEADERS)
+                    .iter()
+                    .filter_map(|hash| blockchain.get_block(hash))
+                    .map(|block| block.header.clone())
+                    .collect();
+                drop(blockchain);
+                peer.send(&Message::Headers(headers))?;
+            }
+            Message::Headers(headers) => self.handle_headers(peer, headers)?,
+            Message::Block(block) => {
+                let unrequested = self.sync.lock().unwrap().block_received(peer.addr, block);
+                match unrequested {
+                    None => {
+                        self.connect_downloaded();
+                        self.request_blocks();
+                    }
+                    Some(block) => self.handle_block(peer, block)?,
+                }
+            }
+            Message::Tx(transaction) => {
+                let hash = transaction.hash();
+                if self.blockchain().add_to_mempool(transaction).is_ok() {
+                    self.broadcast(
+                        &Message::Inv(vec![Inventory::Transaction(hash)]),
+                        Some(peer.addr),
+                    );
+                }
+            }
+            Message::Ping(nonce) => peer.send(&Message::Pong(nonce))?,
+            Message::Pong(_) => {}
+        }
+        Ok(())
+    }
+
+    fn handle_headers(&self, peer: &Peer, headers: Vec<BlockHeader>) -> io::Result<()> {
+        let full = headers.len() == MAX_HEADERS;
+        let blockchain = self.blockchain();
+        let mut sync = self.sync.lock().unwrap();
+        let height = sync
+            .accept_headers(peer.addr, headers, &blockchain)
+            .map_err(misbehaving)?;
+        peer.saw_height(height);
+        let locator = sync.locator(&blockchain);
+        drop(sync);
+        drop(blockchain);
+        if full {
+            peer.send(&Message::GetHeaders { locator })?;
+        }
+        self.request_blocks();
+        Ok(())
+    }
+
+    /// connect downloaded blocks in header order for as long as
+    /// the next one is there
+    fn connect_downloaded(&self) {
+        let mut blockchain = self.blockchain();
+        let mut sync = self.sync.lock().unwrap();
+        let mut misbehaving = vec![];
+        let mut connected = false;
+        while let Some((block, from)) = sync.next_block() {
+            let hash = block.hash();
+            if blockchain.get_block(&hash).is_some() {
+                sync.block_connected();
+                continue;
+            }
+            match blockchain.add_block(block) {
+                Ok(status) => {
+                    log_status(&hash, status);
+                    sync.block_connected();
+                    connected = true;
+                }
+                Err(BtcError::InvalidMerkleRoot | BtcError::DuplicateTransaction) => {
+                    // the body does not match the header, get it elsewhere
+                    println!("{} sent a bad body for block {}", from, hash);
+                    misbehaving.push(from);
+                    break;
+                }
+                Err(e) => {
+                    // the header commits to the body, so nothing past it
+                    // can be connected. only the sender of the body is
+                    // known to be on an invalid chain, whoever sent the
+                    // headers may have been told about it in good faith
+                    println!("block {} from {} is invalid: {}", hash, from, e);
+                    misbehaving.push(from);
+                    sync.abandon();
+                    break;
+                }
+            }
+        }
+        let done = !sync.is_syncing();
+        drop(sync);
+        if connected {
+            if let Err(e) = blockchain.flush() {
+                eprintln!("failed to store blocks: {}", e);
+            }
+            println!(
+                "synced to height {}, tip {}",
+                blockchain.block_height(),
+                blockchain.tip_hash()
+            );
+        }
+        let tip = blockchain.tip_hash();
+        drop(blockchain);
+        if connected && done {
+            self.broadcast(&Message::Inv(vec![Inventory::Block(tip)]), None);
+        }
+        for addr in misbehaving {
+            self.disconnect(addr);
+        }
+    }
+
+    fn handle_block(&self, peer: &Peer, block: Block) -> io::Result<()> {
+        let hash = block.hash();
+        let mut blockchain = self.blockchain();
+        if blockchain.get_block(&hash).is_some() {
+            return Ok(());
+        }
+        if blockchain
+            .get_block(&block.header.prev_block_hash)
+            .is_none()
+        {
+            // we are missing blocks in between, sync from this peer
+            drop(blockchain);
+            return peer.send(&self.get_headers_message());
+        }
+        match blockchain.add_block(block) {
+            Ok(status) => {
+                log_status(&hash, status);
+                if let Err(e) = blockchain.flush() {
+                    eprintln!("failed to store block {}: {}", hash, e);
+                }
+                println!(
+                    "accepted block {} from {}, height {}",
+                    hash,
+                    peer.addr,
+                    blockchain.block_height()
+                );
+                drop(blockchain);
+                self.broadcast(&Message::Inv(vec![Inventory::Block(hash)]), Some(peer.addr));
+            }
+            Err(e) => println!("rejected block {} from {}: {}", hash, peer.addr, e),
+        }
+        Ok(())
+    }
+}
+
+fn log_status(hash: &Hash, status: BlockStatus) {
+    if let BlockStatus::Reorganized {
+        disconnected,
+        connected,
+    } = status
+    {
+        println!(
+            "reorganized to {}: {} blocks disconnected, {} connected",
+            hash, disconnected, connected
+        );
+    }
+}
+
+// nothing is taken from a peer before its version was checked,
+// and the version is only sent once
+fn check_handshake(message: &Message, handshake_done: bool) -> Result<(), Misbehavior> {
+    match (message, handshake_done) {
+        (Message::Version { .. }, true) => Err(Misbehavior("duplicate version")),
+        (Message::Version { .. }, false) | (_, true) => Ok(()),
+        (_, false) => Err(Misbehavior("message before version")),
+    }
+}
+
+fn misbehaving(Misbehavior(reason): Misbehavior) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version() -> Message {
+        Message::Version {
+            version: PROTOCOL_VERSION,
+            genesis: Hash::zero(),
+            height: 0,
+            tip: Hash::zero(),
+            listen_port: 0,
+        }
+    }
+
+    #[test]
+    fn version_comes_first() {
+        assert!(check_handshake(&version(), false).is_ok());
+        for message in [
+            Message::Verack,
+            Message::Inv(vec![]),
+            Message::GetData(vec![]),
+        ] {
+            assert!(check_handshake(&message, false).is_err());
+            assert!(check_handshake(&message, true).is_ok());
+        }
+    }
+
+    #[test]
+    fn version_is_only_sent_once() {
+        assert!(check_handshake(&version(), true).is_err());
+    }
+}
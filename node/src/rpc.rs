@@ -0,0 +1,452 @@
+use crate::node::Node;
+use lib::crypto::PublicKey;
+use lib::encode::{Decodable, Encodable};
+use lib::script::Script;
+use lib::sha256::Hash;
+use lib::types::{Block, BlockChain, BlockHeader, OutPoint, Transaction, TransactionOutput, Utxo};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+// large enough for a hex encoded block of the biggest network message
+const MAX_REQUEST_SIZE: usize = 2 * crate::network::MAX_MESSAGE_SIZE + 1024;
+// request line plus headers
+const MAX_HEADER_SIZE: u64 = 8 * 1024;
+// connections served at the same time, further ones are turned away
+const MAX_RPC_CONNECTIONS: usize = 16;
+// a client that stops sending does not hold on to its connection forever
+const RPC_TIMEOUT: Duration = Duration::from_secs(30);
+
+// json-rpc error codes, the same ones bitcoind uses
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const NOT_FOUND: i64 = -5;
+const DESERIALIZATION_ERROR: i64 = -22;
+const VERIFY_REJECTED: i64 = -26;
+
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Vec<Value>,
+}
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl ToString) -> Self {
+        RpcError {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+type RpcResult = Result<Value, RpcError>;
+
+impl Node {
+    /// answer json-rpc calls posted over http. only listens on localhost,
+    /// there is no authentication
+    pub fn serve_rpc(self: &Arc<Self>, port: u16) -> io::Result<()> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        println!("json-rpc listening on port {}", port);
+        let node = self.clone();
+        let connections = Arc::new(AtomicUsize::new(0));
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { continue };
+                let Some(slot) = ConnectionSlot::take(&connections) else {
+                    let _ = write_response(&mut stream, "503 Service Unavailable", "");
+                    continue;
+                };
+                let node = node.clone();
+                thread::spawn(move || {
+                    let _slot = slot;
+                    if let Err(e) = node.handle_http(stream) {
+                        eprintln!("rpc connection failed: {}", e);
+                    }
+                });
+            }
+        });
+        Ok(())
+    }
+
+    // one request per connection, the connection is closed after replying
+    fn handle_http(&self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(RPC_TIMEOUT))?;
+        stream.set_write_timeout(Some(RPC_TIMEOUT))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let body = match read_request(&mut reader)? {
+            Ok(body) => body,
+            Err(status) => return write_response(&mut stream, status, ""),
+        };
+        let response = match serde_json::from_slice::<Request>(&body) {
+            Ok(request) => {
+                let result = self.call(&request.method, request.params);
+                response(request.id, result)
+            }
+            Err(e) => response(Value::Null, Err(RpcError::new(PARSE_ERROR, e))),
+        };
+        write_response(&mut stream, "200 OK", &response.to_string())
+    }
+
+    fn call(&self, method: &str, params: Vec<Value>) -> RpcResult {
+        match method {
+            // height of the tip, block_height counts the genesis block too
+            "getblockcount" => Ok(json!(self.blockchain().block_height() - 1)),
+            "getbestblockhash" => Ok(json!(self.blockchain().tip_hash().to_string())),
+            "getdifficulty" => {
+                let blockchain = self.blockchain();
+                let bits = blockchain.next_target(&blockchain.tip_hash());
+                Ok(json!(blockchain.params().difficulty(bits)))
+            }
+            "getblock" => {
+                let blockchain = self.blockchain();
+                let block = find_block(&blockchain, params.first())?;
+                if !verbose(&params)? {
+                    return Ok(json!(to_hex(block)));
+                }
+                let mut view = header_view(&blockchain, &block.header);
+                view["transactions"] = block.transactions.iter().map(transaction_view).collect();
+                Ok(view)
+            }
+            "getblockheader" => {
+                let blockchain = self.blockchain();
+                let block = find_block(&blockchain, params.first())?;
+                if !verbose(&params)? {
+                    return Ok(json!(to_hex(&block.header)));
+                }
+                Ok(header_view(&blockchain, &block.header))
+            }
+            "gettxout" => {
+                let txid = param_hash(params.first())?;
+                let vout = params
+                    .get(1)
+                    .and_then(Value::as_u64)
+                    .and_then(|vout| u32::try_from(vout).ok())
+                    .ok_or_else(|| RpcError::new(INVALID_PARAMS, "expected an output index"))?;
+                let blockchain = self.blockchain();
+                Ok(blockchain
+                    .utxos()
+                    .get(&OutPoint::new(txid, vout))
+                    .map(|utxo| utxo_view(&blockchain, utxo))
+                    .unwrap_or(Value::Null))
+            }
+            "scanutxos" => {
+                let scripts = params
+                    .iter()
+                    .map(|param| from_hex(Some(param)))
+                    .collect::<Result<Vec<Script>, _>>()?;
+                let blockchain = self.blockchain();
+                Ok(blockchain
+                    .utxos()
+                    .iter()
+                    .filter(|(_, utxo)| scripts.contains(&utxo.output.script_pubkey))
+                    .map(|(outpoint, utxo)| {
+                        let mut view = utxo_view(&blockchain, utxo);
+                        view["txid"] = json!(outpoint.txid.to_string());
+                        view["vout"] = json!(outpoint.vout);
+                        view["hex"] = json!(to_hex(&utxo.output));
+                        view
+                    })
+                    .collect())
+            }
+            "getmempool" => {
+                let blockchain = self.blockchain();
+                Ok(blockchain
+                    .mempool()
+                    .sorted_by_fee_rate()
+                    .into_iter()
+                    .map(|entry| {
+                        json!({
+                            "txid": entry.transaction.hash().to_string(),
+                            "fee": entry.fee,
+                            "size": entry.size,
+                            "time": entry.time.to_rfc3339(),
+                            "hex": to_hex(&entry.transaction),
+                        })
+                    })
+                    .collect())
+            }
+            "getblocktemplate" => {
+                let public_key: PublicKey = from_hex(params.first())?;
+                let block = self.blockchain().create_block_template(public_key);
+                Ok(json!(to_hex(&block)))
+            }
+            "sendrawtransaction" => {
+                let transaction: Transaction = from_hex(params.first())?;
+                self.submit_transaction(transaction)
+                    .map(|hash| json!(hash.to_string()))
+                    .map_err(|e| RpcError::new(VERIFY_REJECTED, e))
+            }
+            "submitblock" => {
+                let block: Block = from_hex(params.first())?;
+                self.submit_block(block)
+                    .map(|hash| json!(hash.to_string()))
+                    .map_err(|e| RpcError::new(VERIFY_REJECTED, e))
+            }
+            _ => Err(RpcError::new(METHOD_NOT_FOUND, "method not found")),
+        }
+    }
+}
+
+fn response(id: Value, result: RpcResult) -> Value {
+    match result {
+        Ok(result) => json!({ "result": result, "error": null, "id": id }),
+        Err(e) => json!({
+            "result": null,
+            "error": { "code": e.code, "message": e.message },
+            "id": id,
+        }),
+    }
+}
+
+// the body of a POST request, or the http status to reject it with
+fn read_request(reader: &mut impl BufRead) -> io::Result<Result<Vec<u8>, &'static str>> {
+    let mut headers = reader.take(MAX_HEADER_SIZE);
+    let mut request_line = String::new();
+    headers.read_line(&mut request_line)?;
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        if headers.read_line(&mut line)? == 0 {
+            // cut off by the size limit or the client
+            return Ok(Err("431 Request Header Fields Too Large"));
+        }
+        if line.trim_end().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':')
+            && name.eq_ignore_ascii_case("content-length")
+        {
+            content_length = value.trim().parse().unwrap_or(0);
+        }
+    }
+    if !request_line.starts_with("POST ") {
+        return Ok(Err("405 Method Not Allowed"));
+    }
+    if content_length > MAX_REQUEST_SIZE {
+        return Ok(Err("413 Payload Too Large"));
+    }
+    // the buffer grows with what actually arrives, a large
+    // content length alone does not allocate anything
+    let mut body = vec![];
+    reader.take(content_length as u64).read_to_end(&mut body)?;
+    if body.len() != content_length {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(Ok(body))
+}
+
+// one of the MAX_RPC_CONNECTIONS connections, given back on drop
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    fn take(connections: &Arc<AtomicUsize>) -> Option<Self> {
+        connections
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                (count < MAX_RPC_CONNECTIONS).then_some(count + 1)
+            })
+            .ok()
+            .map(|_| ConnectionSlot(connections.clone()))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn write_response(stream: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+// a block is looked up by hash, or by height on the active chain
+fn find_block<'a>(
+    blockchain: &'a BlockChain,
+    param: Option<&Value>,
+) -> Result<&'a Block, RpcError> {
+    let block = match param {
+        Some(Value::Number(height)) => height
+            .as_u64()
+            .and_then(|height| blockchain.blocks().nth(height as usize)),
+        Some(Value::String(_)) => blockchain.get_block(&param_hash(param)?),
+        _ => {
+            return Err(RpcError::new(
+                INVALID_PARAMS,
+                "expected a block hash or height",
+            ));
+        }
+    };
+    block.ok_or_else(|| RpcError::new(NOT_FOUND, "block not found"))
+}
+
+fn verbose(params: &[Value]) -> Result<bool, RpcError> {
+    match params.get(1) {
+        None => Ok(true),
+        Some(Value::Bool(verbose)) => Ok(*verbose),
+        Some(_) => Err(RpcError::new(INVALID_PARAMS, "verbose must be a boolean")),
+    }
+}
+
+fn param_hash(param: Option<&Value>) -> Result<Hash, RpcError> {
+    param
+        .and_then(Value::as_str)
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, "expected a hash"))?
+        .parse()
+        .map_err(|e| RpcError::new(INVALID_PARAMS, e))
+}
+
+// raw blocks, transactions and scripts travel hex encoded in their
+// canonical encoding
+fn to_hex<T: Encodable>(value: &T) -> String {
+    hex::encode(value.to_bytes())
+}
+
+fn from_hex<T: Decodable>(param: Option<&Value>) -> Result<T, RpcError> {
+    let hex = param
+        .and_then(Value::as_str)
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, "expected a hex string"))?;
+    let bytes = hex::decode(hex).map_err(|e| RpcError::new(DESERIALIZATION_ERROR, e))?;
+    T::from_bytes(&bytes).map_err(|e| RpcError::new(DESERIALIZATION_ERROR, e))
+}
+
+fn header_view(blockchain: &BlockChain, header: &BlockHeader) -> Value {
+    let hash = header.hash();
+    let height = blockchain.block_height_of(&hash).unwrap_or_default();
+    // blocks on a side branch have no confirmations
+    let confirmations = if blockchain.is_in_active_chain(&hash) {
+        blockchain.block_height() - height
+    } else {
+        0
+    };
+    json!({
+        "hash": hash.to_string(),
+        "height": height,
+        "confirmations": confirmations,
+        "prev_block_hash": header.prev_block_hash.to_string(),
+        "merkle_root": header.markle_root.to_string(),
+        "timestamp": header.timestamp.to_rfc3339(),
+        "nonce": header.nonce,
+        "bits": header.bits.to_string(),
+        "target": format!("{:x}", header.target()),
+        "difficulty": blockchain.params().difficulty(header.bits),
+        "chainwork": format!("{:x}", blockchain.chain_work_of(&hash).unwrap_or_default()),
+    })
+}
+
+fn transaction_view(transaction: &Transaction) -> Value {
+    json!({
+        "txid": transaction.hash().to_string(),
+        "inputs": transaction
+            .inputs
+            .iter()
+            .map(|input| json!({
+                "txid": input.previous_output.txid.to_string(),
+                "vout": input.previous_output.vout,
+                "sequence": input.sequence,
+            }))
+            .collect::<Vec<_>>(),
+        "outputs": transaction.outputs.iter().map(output_view).collect::<Vec<_>>(),
+        "lock_time": transaction.lock_time,
+    })
+}
+
+fn utxo_view(blockchain: &BlockChain, utxo: &Utxo) -> Value {
+    let mut view = output_view(&utxo.output);
+    view["height"] = json!(utxo.height);
+    view["coinbase"] = json!(utxo.coinbase);
+    view["mature"] = json!(utxo.is_mature(blockchain.block_height(), blockchain.params()));
+    view
+}
+
+fn output_view(output: &TransactionOutput) -> Value {
+    json!({
+        "value": output.value,
+        "unique_id": output.unique_id.to_string(),
+        "script_pubkey": output.script_pubkey.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(request: &[u8]) -> io::Result<Result<Vec<u8>, &'static str>> {
+        read_request(&mut BufReader::new(request))
+    }
+
+    #[test]
+    fn body_of_a_post_is_read() {
+        let request = b"POST / HTTP/1.1\r\ncontent-length: 2\r\n\r\n{}";
+        assert_eq!(read(request).unwrap(), Ok(b"{}".to_vec()));
+    }
+
+    #[test]
+    fn only_post_is_accepted() {
+        let request = b"GET / HTTP/1.1\r\n\r\n";
+        assert_eq!(read(request).unwrap(), Err("405 Method Not Allowed"));
+    }
+
+    #[test]
+    fn oversized_requests_are_rejected_before_reading_the_body() {
+        let request = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_REQUEST_SIZE + 1
+        );
+        assert_eq!(
+            read(request.as_bytes()).unwrap(),
+            Err("413 Payload Too Large")
+        );
+
+        let mut request = b"POST / HTTP/1.1\r\nX-Padding: ".to_vec();
+        request.extend(vec![b'a'; MAX_HEADER_SIZE as usize]);
+        request.extend(b"\r\n\r\n");
+        assert_eq!(
+            read(&request).unwrap(),
+            Err("431 Request Header Fields Too Large")
+        );
+    }
+
+    #[test]
+    fn body_shorter_than_announced_is_an_error() {
+        // claims the maximum but sends two bytes
+        let request = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n{{}}",
+            MAX_REQUEST_SIZE
+        );
+        let error = read(request.as_bytes()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn connections_are_limited() {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let slots: Vec<ConnectionSlot> = (0..MAX_RPC_CONNECTIONS)
+            .map(|_| ConnectionSlot::take(&connections).unwrap())
+            .collect();
+        assert!(ConnectionSlot::take(&connections).is_none());
+        drop(slots);
+        assert_eq!(connections.load(Ordering::SeqCst), 0);
+        assert!(ConnectionSlot::take(&connections).is_some());
+    }
+}
@@ -0,0 +1,260 @@
+use chrono::{DateTime, TimeDelta, Utc};
+use lib::sha256::Hash;
+use lib::types::{Block, BlockChain, BlockHeader};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// most headers sent in a single headers message
+pub const MAX_HEADERS: usize = 2000;
+// blocks requested from a single peer at once
+const MAX_BLOCKS_IN_FLIGHT: usize = 16;
+// only this many headers past the tip are downloaded at once, so
+// blocks that arrive out of order can not pile up without bound
+const DOWNLOAD_WINDOW: usize = 1024;
+// a peer that does not deliver a requested block within this time stalls
+pub const BLOCK_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(10);
+// peers are dropped after stalling this many times
+const MAX_STALLS: u32 = 3;
+// a block nobody delivers after this many requests probably does not exist
+const MAX_DOWNLOAD_ATTEMPTS: u32 = 5;
+
+/// The peer sent something provably wrong and should be disconnected
+#[derive(Debug)]
+pub struct Misbehavior(pub &'static str);
+
+struct PendingHeader {
+    hash: Hash,
+    header: BlockHeader,
+    /// Height the block will have once connected
+    height: u64,
+    attempts: u32,
+}
+
+/// State of the headers-first initial block download.
+///
+/// Headers are fetched and checked first, so only blocks on a proof of
+/// work chain are ever downloaded. Bodies are then spread over every peer
+/// that has them and connected in header order as they come in.
+#[derive(Default)]
+pub struct HeaderSync {
+    /// Validated headers whose blocks are not connected yet, in chain order
+    pending: Vec<PendingHeader>,
+    /// Peer that sent the pending headers
+    headers_from: Option<SocketAddr>,
+    in_flight: HashMap<Hash, (SocketAddr, Instant)>,
+    /// Downloaded blocks waiting for their parent, with who sent them
+    received: HashMap<Hash, (Block, SocketAddr)>,
+    stalls: HashMap<SocketAddr, u32>,
+}
+
+impl HeaderSync {
+    pub fn is_syncing(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    pub fn headers_from(&self) -> Option<SocketAddr> {
+        self.headers_from
+    }
+
+    /// locator covering the pending headers as well as the chain
+    pub fn locator(&self, blockchain: &BlockChain) -> Vec<Hash> {
+        let mut locator: Vec<Hash> = self
+            .pending
+            .iter()
+            .rev()
+            .step_by(100)
+            .map(|pending| pending.hash)
+            .collect();
+        locator.extend(blockchain.block_locator());
+        locator
+    }
+
+    /// check a batch of headers and queue their blocks for download.
+    /// returns the height reached by the peer's headers
+    pub fn accept_headers(
+        &mut self,
+        from: SocketAddr,
+        headers: Vec<BlockHeader>,
+        blockchain: &BlockChain,
+    ) -> Result<u64, Misbehavior> {
+        if headers.len() > MAX_HEADERS {
+            return Err(Misbehavior("too many headers"));
+        }
+        let params = blockchain.params();
+        let mut height = 0;
+        for header in headers {
+            let hash = header.hash();
+            if let Some(known) = blockchain.block_height_of(&hash) {
+                height = known + 1;
+                continue;
+            }
+            if let Some(pending) = self.pending.iter().find(|pending| pending.hash == hash) {
+                height = pending.height + 1;
+                continue;
+            }
+            // every header has to extend the pending headers, or the
+            // chain if there are none
+            let parent = match self.pending.last() {
+                Some(last) => (last.hash == header.prev_block_hash)
+                    .then_some((last.height + 1, last.header.bits)),
+                None => blockchain
+                    .block_height_of(&header.prev_block_hash)
+                    .zip(blockchain.get_block(&header.prev_block_hash))
+                    .map(|(height, block)| (height + 1, block.header.bits)),
+            };
+            let Some((header_height, parent_bits)) = parent else {
+                // a competing header chain, finish the current one first
+                if self.pending.is_empty() {
+                    return Err(Misbehavior("headers do not connect"));
+                }
+                return Ok(height);
+            };
+            let expected_bits = params.next_target(
+                header_height,
+                parent_bits,
+                self.timestamps_before(&header, blockchain),
+            );
+            if header.bits != expected_bits {
+                return Err(Misbehavior("header with the wrong target"));
+            }
+            if !hash.matches_target(header.target()) {
+                return Err(Misbehavior("header without enough proof of work"));
+            }
+            let median_time_past =
+                params.median_time_past(self.timestamps_before(&header, blockchain));
+            if header.timestamp <= median_time_past {
+                return Err(Misbehavior("header timestamp is too old"));
+            }
+            let max_future = TimeDelta::seconds(params.max_future_block_time as i64);
+            if header.timestamp > blockchain.clock().now() + max_future {
+                return Err(Misbehavior("header timestamp is too far in the future"));
+            }
+            height = header_height + 1;
+            self.pending.push(PendingHeader {
+                hash,
+                header,
+                height: header_height,
+                attempts: 0,
+            });
+            self.headers_from = Some(from);
+        }
+        Ok(height)
+    }
+
+    // timestamps of the parent of `header` and the blocks before it, newest
+    // first. the parent is the last pending header or a block of the chain
+    fn timestamps_before<'a>(
+        &'a self,
+        header: &BlockHeader,
+        blockchain: &'a BlockChain,
+    ) -> impl Iterator<Item = DateTime<Utc>> + 'a {
+        let chain_from = self
+            .pending
+            .first()
+            .map_or(header.prev_block_hash, |first| first.header.prev_block_hash);
+        let pending = self
+            .pending
+            .iter()
+            .rev()
+            .map(|pending| pending.header.timestamp);
+        pending.chain(blockchain.timestamps_from(&chain_from))
+    }
+
+    /// blocks to ask `peer` for, given the number of blocks it claims to have
+    pub fn next_requests(&mut self, peer: SocketAddr, peer_height: u64) -> Vec<Hash> {
+        let in_flight = self
+            .in_flight
+            .values()
+            .filter(|(addr, _)| *addr == peer)
+            .count();
+        let mut requests = vec![];
+        for pending in self.pending.iter_mut().take(DOWNLOAD_WINDOW) {
+            if in_flight + requests.len() >= MAX_BLOCKS_IN_FLIGHT {
+                break;
+            }
+            if pending.height >= peer_height
+                || self.in_flight.contains_key(&pending.hash)
+                || self.received.contains_key(&pending.hash)
+            {
+                continue;
+            }
+            pending.attempts += 1;
+            self.in_flight.insert(pending.hash, (peer, Instant::now()));
+            requests.push(pending.hash);
+        }
+        requests
+    }
+
+    /// hands the block back if it was not asked for
+    pub fn block_received(&mut self, from: SocketAddr, block: Block) -> Option<Block> {
+        let hash = block.hash();
+        if self.in_flight.remove(&hash).is_none() {
+            return Some(block);
+        }
+        self.stalls.remove(&from);
+        self.received.insert(hash, (block, from));
+        None
+    }
+
+    /// the next block to connect, if it has been downloaded
+    pub fn next_block(&mut self) -> Option<(Block, SocketAddr)> {
+        let first = self.pending.first()?;
+        self.received.remove(&first.hash)
+    }
+
+    /// the block handed out by `next_block` made it onto the chain.
+    /// if it did not but the header is fine, the block is simply
+    /// requested again from whoever is free
+    pub fn block_connected(&mut self) {
+        if !self.pending.is_empty() {
+            self.pending.remove(0);
+        }
+        if self.pending.is_empty() {
+            self.headers_from = None;
+        }
+    }
+
+    /// the header chain itself is invalid, forget all of it
+    pub fn abandon(&mut self) {
+        self.pending.clear();
+        self.in_flight.clear();
+        self.received.clear();
+        self.headers_from = None;
+    }
+
+    pub fn peer_disconnected(&mut self, peer: SocketAddr) {
+        self.in_flight.retain(|_, (addr, _)| *addr != peer);
+        self.received.retain(|_, (_, addr)| *addr != peer);
+        self.stalls.remove(&peer);
+        if self.headers_from == Some(peer) {
+            self.headers_from = None;
+        }
+    }
+
+    /// give up on requests that took too long. returns peers that stalled
+    /// too often, and whether the pending headers look like a lie
+    pub fn check_timeouts(&mut self) -> (Vec<SocketAddr>, bool) {
+        let now = Instant::now();
+        let timed_out: Vec<(Hash, SocketAddr)> = self
+            .in_flight
+            .iter()
+            .filter(|(_, (_, requested))| now - *requested > BLOCK_DOWNLOAD_TIMEOUT)
+            .map(|(hash, (addr, _))| (*hash, *addr))
+            .collect();
+        let mut stalled = HashSet::new();
+        for (hash, addr) in timed_out {
+            self.in_flight.remove(&hash);
+            let stalls = self.stalls.entry(addr).or_default();
+            *stalls += 1;
+            if *stalls >= MAX_STALLS {
+                stalled.insert(addr);
+            }
+        }
+        let unavailable = self
+            .pending
+            .iter()
+            .any(|pending| pending.attempts >= MAX_DOWNLOAD_ATTEMPTS);
+        (stalled.into_iter().collect(), unavailable)
+    }
+}
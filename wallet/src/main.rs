@@ -1,3 +1,220 @@
+mod wallet;
+
+use lib::crypto::PublicKey;
+use lib::encode::Decodable;
+use lib::params::{ChainParams, Network};
+use lib::partial::PartialTransaction;
+use lib::rpc::RpcClient;
+use lib::script::Script;
+use wallet::{HistoryKind, Wallet, broadcast, decode_hex, encode_hex};
+
+const DEFAULT_WALLET_DIR: &str = "./wallet";
+// the node is expected on its network's default rpc port
+const DEFAULT_RPC_HOST: &str = "127.0.0.1";
+// fee per byte used when send is not given one
+const DEFAULT_FEE_RATE: u64 = 1;
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: wallet [--dir <dir>] [--network <mainnet|testnet|regtest>] [--rpc <host:port>] <command>"
+    );
+    eprintln!();
+    eprintln!("commands:");
+    eprintln!("  newkey     generate a key and print its public key");
+    eprintln!("  keys       list the wallet's public keys");
+    eprintln!("  balance    show the confirmed and unconfirmed balance");
+    eprintln!("  history    list outputs received and spent by the wallet");
+    eprintln!("  send <public key | script> <amount> [fee rate]");
+    eprintln!("             pay someone, change goes back to the wallet");
+    eprintln!("  multisig <required> <public key>...");
+    eprintln!("             add a multisig script sharing the wallet's keys");
+    eprintln!("  multisigs  list the multisig scripts and their balance");
+    eprintln!("  propose <multisig script> <public key | script> <amount> [fee rate]");
+    eprintln!("             start a payment from a multisig script");
+    eprintln!("  sign <partial transaction>");
+    eprintln!("             add the wallet's signatures to a proposed payment");
+    eprintln!("  combine <partial transaction>...");
+    eprintln!("             merge signatures collected separately");
+    eprintln!("  broadcast <partial transaction>");
+    eprintln!("             send a payment once it has enough signatures");
+    std::process::exit(1);
+}
+
 fn main() {
-    println!("Hello, world!");
+    let mut dir = DEFAULT_WALLET_DIR.to_string();
+    let mut network = Network::Mainnet;
+    let mut rpc_addr = None;
+    let mut command = vec![];
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dir" => dir = args.next().unwrap_or_else(|| usage()),
+            "--network" => {
+                network = args
+                    .next()
+                    .and_then(|network| network.parse().ok())
+                    .unwrap_or_else(|| usage())
+            }
+            "--rpc" => rpc_addr = Some(args.next().unwrap_or_else(|| usage())),
+            _ => command.push(arg),
+        }
+    }
+    let Some((command, command_args)) = command.split_first() else {
+        usage()
+    };
+
+    let mut wallet = Wallet::open(&dir).unwrap_or_else(|e| {
+        eprintln!("failed to open wallet in {}: {}", dir, e);
+        std::process::exit(1);
+    });
+    let rpc_addr = rpc_addr.unwrap_or_else(|| {
+        let port = ChainParams::for_network(network).default_rpc_port;
+        format!("{}:{}", DEFAULT_RPC_HOST, port)
+    });
+    let rpc = RpcClient::new(rpc_addr);
+    let result = match command.as_str() {
+        "newkey" => wallet
+            .generate_key()
+            .map(|public_key| println!("{}", encode_hex(&public_key))),
+        "keys" => {
+            for public_key in wallet.public_keys() {
+                println!("{}", encode_hex(&public_key));
+            }
+            Ok(())
+        }
+        "balance" => wallet.refresh(&rpc).map(|balance| {
+            println!("confirmed:   {}", balance.confirmed);
+            println!("immature:    {}", balance.immature);
+            println!("unconfirmed: {:+}", balance.unconfirmed);
+        }),
+        "history" => wallet.refresh(&rpc).map(|_| {
+            for entry in wallet.history() {
+                let (sign, kind) = match entry.kind {
+                    HistoryKind::Received => ('+', "received"),
+                    HistoryKind::Spent => ('-', "spent"),
+                };
+                println!(
+                    "{}  {:<8} {}{:<12} {}{}",
+                    entry.time.format("%Y-%m-%d %H:%M:%S"),
+                    kind,
+                    sign,
+                    entry.value,
+                    entry.output,
+                    if entry.confirmed {
+                        ""
+                    } else {
+                        "  (unconfirmed)"
+                    }
+                );
+            }
+        }),
+        "send" => {
+            let [recipient, amount, rest @ ..] = command_args else {
+                usage()
+            };
+            let recipient = parse_recipient(recipient);
+            let amount = amount.parse().unwrap_or_else(|_| usage());
+            let fee_rate = parse_fee_rate(rest);
+            wallet
+                .send(&rpc, recipient, amount, fee_rate)
+                .map(|hash| println!("{}", hash))
+        }
+        "multisig" => {
+            let [required, public_keys @ ..] = command_args else {
+                usage()
+            };
+            let required = required.parse().unwrap_or_else(|_| usage());
+            let public_keys: Vec<PublicKey> = public_keys
+                .iter()
+                .map(|public_key| parse_hex(public_key, "public key"))
+                .collect();
+            wallet
+                .add_multisig(required, &public_keys)
+                .map(|script| println!("{}", encode_hex(&script)))
+        }
+        "multisigs" => wallet.multisigs().iter().try_for_each(|script| {
+            let balance = wallet.multisig_balance(&rpc, script)?;
+            println!("{}  {}", encode_hex(script), balance);
+            Ok(())
+        }),
+        "propose" => {
+            let [multisig, recipient, amount, rest @ ..] = command_args else {
+                usage()
+            };
+            let multisig: Script = parse_hex(multisig, "script");
+            let recipient = parse_recipient(recipient);
+            let amount = amount.parse().unwrap_or_else(|_| usage());
+            let fee_rate = parse_fee_rate(rest);
+            wallet
+                .propose(&rpc, &multisig, recipient, amount, fee_rate)
+                .map(|partial| print_partial(&partial))
+        }
+        "sign" => {
+            let [partial] = command_args else { usage() };
+            let mut partial = parse_partial(partial);
+            wallet.sign(&mut partial).map(|signed| {
+                eprintln!("signed {} inputs", signed);
+                print_partial(&partial);
+            })
+        }
+        "combine" => {
+            let [first, rest @ ..] = command_args else {
+                usage()
+            };
+            let mut partial = parse_partial(first);
+            rest.iter()
+                .try_for_each(|other| partial.combine(parse_partial(other)))
+                .map(|()| print_partial(&partial))
+                .map_err(std::io::Error::other)
+        }
+        "broadcast" => {
+            let [partial] = command_args else { usage() };
+            let partial = parse_partial(partial);
+            partial
+                .finalize()
+                .map_err(|_| std::io::Error::other("not enough signatures"))
+                .and_then(|transaction| broadcast(&rpc, &transaction))
+                .map(|hash| println!("{}", hash))
+        }
+        _ => usage(),
+    };
+    if let Err(e) = result {
+        eprintln!("{} failed: {}", command, e);
+        std::process::exit(1);
+    }
+}
+
+fn parse_hex<T: Decodable>(arg: &str, what: &str) -> T {
+    decode_hex(&serde_json::json!(arg)).unwrap_or_else(|e| {
+        eprintln!("invalid {}: {}", what, e);
+        std::process::exit(1);
+    })
+}
+
+fn parse_partial(arg: &str) -> PartialTransaction {
+    parse_hex(arg, "partial transaction")
+}
+
+// a public key is paid to its hash, anything else has to be a script
+fn parse_recipient(arg: &str) -> Script {
+    match decode_hex::<PublicKey>(&serde_json::json!(arg)) {
+        Ok(public_key) => Script::p2pkh(&public_key),
+        Err(_) => parse_hex(arg, "public key or script"),
+    }
+}
+
+fn parse_fee_rate(args: &[String]) -> u64 {
+    match args {
+        [] => DEFAULT_FEE_RATE,
+        [fee_rate] => fee_rate.parse().unwrap_or_else(|_| usage()),
+        _ => usage(),
+    }
+}
+
+// the hex goes to stdout for the next signer, progress to stderr
+fn print_partial(partial: &PartialTransaction) {
+    if !partial.is_complete() {
+        eprintln!("more signatures needed");
+    }
+    println!("{}", encode_hex(partial));
 }
@@ -0,0 +1,561 @@
+use chrono::{DateTime, Utc};
+use lib::builder::TransactionBuilder;
+use lib::crypto::{PrivateKey, PublicKey};
+use lib::encode::{Decodable, Encodable};
+use lib::partial::PartialTransaction;
+use lib::rpc::RpcClient;
+use lib::script::{MAX_MULTISIG_KEYS, Script};
+use lib::sha256::Hash;
+use lib::types::{OutPoint, Transaction, TransactionOutput};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+const KEYS_FILE: &str = "keys.cbor";
+const HISTORY_FILE: &str = "history.cbor";
+const MULTISIG_FILE: &str = "multisig.cbor";
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum HistoryKind {
+    Received,
+    Spent,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub kind: HistoryKind,
+    /// Output that was received or spent
+    pub output: OutPoint,
+    pub value: u64,
+    pub confirmed: bool,
+    /// When the wallet first noticed
+    pub time: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+enum OutputState {
+    /// Created by a mempool transaction
+    Unconfirmed,
+    Confirmed,
+    /// Confirmed coinbase output that can not be spent yet
+    Immature,
+    /// Confirmed, but spent by a mempool transaction
+    Spending,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TrackedOutput {
+    output: TransactionOutput,
+    state: OutputState,
+}
+
+// everything the wallet saw on its last refresh plus what happened before
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct History {
+    outputs: HashMap<OutPoint, TrackedOutput>,
+    entries: Vec<HistoryEntry>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Balance {
+    /// Value of confirmed outputs that can be spent
+    pub confirmed: u64,
+    /// Value of coinbase outputs waiting to mature
+    pub immature: u64,
+    /// Net change the mempool will make once confirmed
+    pub unconfirmed: i128,
+}
+
+/// Keys plus the outputs locked to them, stored in a directory
+pub struct Wallet {
+    dir: PathBuf,
+    keys: Vec<PrivateKey>,
+    /// Multisig scripts the wallet holds a share of
+    multisigs: Vec<Script>,
+    history: History,
+}
+
+impl Wallet {
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let keys = read_file(&dir.join(KEYS_FILE))?.unwrap_or_default();
+        let multisigs = read_file(&dir.join(MULTISIG_FILE))?.unwrap_or_default();
+        let history = read_file(&dir.join(HISTORY_FILE))?.unwrap_or_default();
+        Ok(Wallet {
+            dir,
+            keys,
+            multisigs,
+            history,
+        })
+    }
+
+    pub fn public_keys(&self) -> Vec<PublicKey> {
+        self.keys.iter().map(PrivateKey::public_key).collect()
+    }
+
+    // every locking script the wallet can spend
+    fn scripts(&self) -> Vec<Script> {
+        self.public_keys()
+            .iter()
+            .flat_map(|public_key| [Script::p2pkh(public_key), Script::p2pk(public_key)])
+            .collect()
+    }
+
+    pub fn generate_key(&mut self) -> io::Result<PublicKey> {
+        let key = PrivateKey::new_key();
+        let public_key = key.public_key();
+        self.keys.push(key);
+        write_file(&self.dir.join(KEYS_FILE), &self.keys)?;
+        Ok(public_key)
+    }
+
+    /// remember a `required` of `public_keys` script, at least one of the
+    /// keys has to be the wallet's
+    pub fn add_multisig(
+        &mut self,
+        required: usize,
+        public_keys: &[PublicKey],
+    ) -> io::Result<Script> {
+        if required == 0 || required > public_keys.len() {
+            return Err(invalid("required signatures out of range"));
+        }
+        if public_keys.len() > MAX_MULTISIG_KEYS {
+            return Err(invalid("too many keys"));
+        }
+        let own_keys = self.public_keys();
+        if !public_keys
+            .iter()
+            .any(|public_key| own_keys.contains(public_key))
+        {
+            return Err(invalid("none of the keys belong to the wallet"));
+        }
+        let script = Script::multisig(required, public_keys);
+        if !self.multisigs.contains(&script) {
+            self.multisigs.push(script.clone());
+            write_file(&self.dir.join(MULTISIG_FILE), &self.multisigs)?;
+        }
+        Ok(script)
+    }
+
+    pub fn multisigs(&self) -> &[Script] {
+        &self.multisigs
+    }
+
+    /// confirmed value locked with a multisig script
+    pub fn multisig_balance(&self, rpc: &RpcClient, script: &Script) -> io::Result<u64> {
+        Ok(scan(rpc, std::slice::from_ref(script))?
+            .values()
+            .map(|(output, _)| output.value)
+            .sum())
+    }
+
+    pub fn history(&self) -> &[HistoryEntry] {
+        &self.history.entries
+    }
+
+    /// fetch the wallet's outputs from the node, record what changed
+    /// since the last refresh and return the balance
+    pub fn refresh(&mut self, rpc: &RpcClient) -> io::Result<Balance> {
+        let scripts = self.scripts();
+        let mut outputs: HashMap<OutPoint, TrackedOutput> = scan(rpc, &scripts)?
+            .into_iter()
+            .map(|(outpoint, (output, mature))| {
+                let state = if mature {
+                    OutputState::Confirmed
+                } else {
+                    OutputState::Immature
+                };
+                let tracked = TrackedOutput { output, state };
+                (outpoint, tracked)
+            })
+            .collect();
+        for entry in as_array(rpc.call("getmempool", json!([]))?)? {
+            let transaction: Transaction = decode_hex(&entry["hex"])?;
+            for input in &transaction.inputs {
+                if let Some(output) = outputs.get_mut(&input.previous_output) {
+                    output.state = OutputState::Spending;
+                }
+            }
+            let txid = transaction.hash();
+            for (vout, output) in transaction.outputs.iter().enumerate() {
+                if scripts.contains(&output.script_pubkey) {
+                    outputs.insert(
+                        OutPoint::new(txid, vout as u32),
+                        TrackedOutput {
+                            output: output.clone(),
+                            state: OutputState::Unconfirmed,
+                        },
+                    );
+                }
+            }
+        }
+
+        self.record_changes(&outputs);
+        self.history.outputs = outputs;
+        write_file(&self.dir.join(HISTORY_FILE), &self.history)?;
+        Ok(self.balance())
+    }
+
+    pub fn balance(&self) -> Balance {
+        let mut balance = Balance {
+            confirmed: 0,
+            immature: 0,
+            unconfirmed: 0,
+        };
+        for tracked in self.history.outputs.values() {
+            let value = tracked.output.value;
+            match tracked.state {
+                OutputState::Unconfirmed => balance.unconfirmed += value as i128,
+                OutputState::Confirmed => balance.confirmed += value,
+                OutputState::Immature => balance.immature += value,
+                OutputState::Spending => {
+                    balance.confirmed += value;
+                    balance.unconfirmed -= value as i128;
+                }
+            }
+        }
+        balance
+    }
+
+    /// pay `value` to `recipient` out of confirmed outputs not already
+    /// being spent, returns the hash of the broadcast transaction
+    pub fn send(
+        &mut self,
+        rpc: &RpcClient,
+        recipient: Script,
+        value: u64,
+        fee_rate: u64,
+    ) -> io::Result<Hash> {
+        self.refresh(rpc)?;
+        let spendable = self
+            .history
+            .outputs
+            .iter()
+            .filter(|(_, tracked)| tracked.state == OutputState::Confirmed)
+            .map(|(hash, tracked)| (*hash, tracked.output.clone()));
+        let mut builder = TransactionBuilder::new()
+            .add_output(recipient, value)
+            .add_utxos(spendable)
+            .fee_rate(fee_rate);
+        for key in &self.keys {
+            builder = builder.add_key(key.clone());
+        }
+        let transaction = builder.build().map_err(io::Error::other)?;
+        let hash = broadcast(rpc, &transaction)?;
+        self.refresh(rpc)?;
+        Ok(hash)
+    }
+
+    /// start a payment out of the confirmed outputs of `multisig`, signed
+    /// with the wallet's share of the keys. change goes back to `multisig`
+    pub fn propose(
+        &self,
+        rpc: &RpcClient,
+        multisig: &Script,
+        recipient: Script,
+        value: u64,
+        fee_rate: u64,
+    ) -> io::Result<PartialTransaction> {
+        let (required, public_keys) = multisig
+            .multisig_keys()
+            .ok_or_else(|| invalid("not a multisig script"))?;
+        let mut builder = TransactionBuilder::new()
+            .add_output(recipient, value)
+            .add_utxos(
+                scan(rpc, std::slice::from_ref(multisig))?
+                    .into_iter()
+                    .filter(|(_, (_, mature))| *mature)
+                    .map(|(outpoint, (output, _))| (outpoint, output)),
+            )
+            .add_multisig(required, &public_keys)
+            .fee_rate(fee_rate);
+        for key in &self.keys {
+            builder = builder.add_key(key.clone());
+        }
+        builder.build_partial().map_err(io::Error::other)
+    }
+
+    /// add the wallet's signatures, returns how many inputs it signed
+    pub fn sign(&self, partial: &mut PartialTransaction) -> io::Result<usize> {
+        let mut signed = 0;
+        for key in &self.keys {
+            signed += partial.sign(key).map_err(io::Error::other)?;
+        }
+        Ok(signed)
+    }
+
+    // compare against the outputs seen last time and extend the history
+    fn record_changes(&mut self, outputs: &HashMap<OutPoint, TrackedOutput>) {
+        use OutputState::*;
+        for (hash, tracked) in outputs {
+            let value = tracked.output.value;
+            let previous = self.history.outputs.get(hash).map(|tracked| tracked.state);
+            match (previous, tracked.state) {
+                (None, state) => {
+                    self.push(HistoryKind::Received, *hash, value, state != Unconfirmed);
+                    if state == Spending {
+                        self.push(HistoryKind::Spent, *hash, value, false);
+                    }
+                }
+                (Some(Unconfirmed), Confirmed | Spending) => {
+                    self.confirm(HistoryKind::Received, hash);
+                    if tracked.state == Spending {
+                        self.push(HistoryKind::Spent, *hash, value, false);
+                    }
+                }
+                (Some(Confirmed), Spending) => {
+                    self.push(HistoryKind::Spent, *hash, value, false);
+                }
+                // the spending transaction left the mempool without confirming
+                (Some(Spending), Confirmed) => self.forget(HistoryKind::Spent, hash),
+                _ => {}
+            }
+        }
+        let gone: Vec<(OutPoint, u64, OutputState)> = self
+            .history
+            .outputs
+            .iter()
+            .filter(|(hash, _)| !outputs.contains_key(hash))
+            .map(|(hash, tracked)| (*hash, tracked.output.value, tracked.state))
+            .collect();
+        for (hash, value, state) in gone {
+            match state {
+                // dropped from the mempool without confirming
+                Unconfirmed => self.forget(HistoryKind::Received, &hash),
+                // an immature coinbase can not be spent, it only goes
+                // away when its block is reorganized out
+                Immature => self.forget(HistoryKind::Received, &hash),
+                Confirmed => self.push(HistoryKind::Spent, hash, value, true),
+                Spending => self.confirm(HistoryKind::Spent, &hash),
+            }
+        }
+    }
+
+    fn push(&mut self, kind: HistoryKind, output: OutPoint, value: u64, confirmed: bool) {
+        self.history.entries.push(HistoryEntry {
+            kind,
+            output,
+            value,
+            confirmed,
+            time: Utc::now(),
+        });
+    }
+
+    fn confirm(&mut self, kind: HistoryKind, output: &OutPoint) {
+        if let Some(entry) = self
+            .history
+            .entries
+            .iter_mut()
+            .rev()
+            .find(|entry| entry.kind == kind && entry.output == *output)
+        {
+            entry.confirmed = true;
+        }
+    }
+
+    // drop the latest entry of `kind` for `output`, it never happened
+    fn forget(&mut self, kind: HistoryKind, output: &OutPoint) {
+        if let Some(index) = self
+            .history
+            .entries
+            .iter()
+            .rposition(|entry| entry.kind == kind && entry.output == *output)
+        {
+            self.history.entries.remove(index);
+        }
+    }
+}
+
+/// send a finished transaction to the node, returns its hash
+pub fn broadcast(rpc: &RpcClient, transaction: &Transaction) -> io::Result<Hash> {
+    let hash = rpc.call("sendrawtransaction", json!([encode_hex(transaction)]))?;
+    hash.as_str()
+        .and_then(|hash| hash.parse().ok())
+        .ok_or_else(|| invalid("bad transaction hash"))
+}
+
+// confirmed outputs locked with any of `scripts`, and whether they can
+// be spent already
+fn scan(
+    rpc: &RpcClient,
+    scripts: &[Script],
+) -> io::Result<HashMap<OutPoint, (TransactionOutput, bool)>> {
+    let mut outputs = HashMap::new();
+    if scripts.is_empty() {
+        return Ok(outputs);
+    }
+    let params: Vec<String> = scripts.iter().map(encode_hex).collect();
+    for utxo in as_array(rpc.call("scanutxos", json!(params))?)? {
+        let txid = utxo["txid"]
+            .as_str()
+            .and_then(|txid| txid.parse().ok())
+            .ok_or_else(|| invalid("bad utxo txid"))?;
+        let vout = utxo["vout"]
+            .as_u64()
+            .and_then(|vout| u32::try_from(vout).ok())
+            .ok_or_else(|| invalid("bad utxo index"))?;
+        let mature = utxo["mature"].as_bool().unwrap_or(true);
+        outputs.insert(
+            OutPoint::new(txid, vout),
+            (decode_hex(&utxo["hex"])?, mature),
+        );
+    }
+    Ok(outputs)
+}
+
+/// keys, scripts, transactions and partial transactions in their
+/// canonical encoding, the same hex the node's rpc takes and returns
+pub fn encode_hex<T: Encodable>(value: &T) -> String {
+    hex::encode(value.to_bytes())
+}
+
+pub fn decode_hex<T: Decodable>(value: &Value) -> io::Result<T> {
+    let bytes = value
+        .as_str()
+        .and_then(|hex| hex::decode(hex).ok())
+        .ok_or_else(|| invalid("expected a hex string"))?;
+    T::from_bytes(&bytes).map_err(|e| invalid(&e.to_string()))
+}
+
+fn as_array(value: Value) -> io::Result<Vec<Value>> {
+    match value {
+        Value::Array(values) => Ok(values),
+        _ => Err(invalid("expected an array")),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn read_file<T: DeserializeOwned>(path: &Path) -> io::Result<Option<T>> {
+    match File::open(path) {
+        Ok(file) => ciborium::from_reader(file)
+            .map(Some)
+            .map_err(|e| invalid(&e.to_string())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// written to a temporary file first so a crash never leaves half a file
+fn write_file<T: Serialize>(path: &Path, value: &T) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    ciborium::into_writer(value, &mut file).map_err(|e| invalid(&e.to_string()))?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lib::params::ChainParams;
+    use lib::types::BlockChain;
+
+    fn wallet() -> Wallet {
+        Wallet {
+            dir: PathBuf::new(),
+            keys: vec![],
+            multisigs: vec![],
+            history: History::default(),
+        }
+    }
+
+    fn output() -> (OutPoint, TransactionOutput) {
+        let blockchain = BlockChain::new(ChainParams::regtest());
+        let public_key = PrivateKey::new_key().public_key();
+        let coinbase = blockchain.create_block_template(public_key).transactions[0].clone();
+        (
+            OutPoint::new(coinbase.hash(), 0),
+            coinbase.outputs[0].clone(),
+        )
+    }
+
+    // what a refresh does once the outputs are fetched
+    fn refresh_with(wallet: &mut Wallet, outputs: &[(OutPoint, TransactionOutput, OutputState)]) {
+        let outputs: HashMap<OutPoint, TrackedOutput> = outputs
+            .iter()
+            .map(|(outpoint, output, state)| {
+                let tracked = TrackedOutput {
+                    output: output.clone(),
+                    state: *state,
+                };
+                (*outpoint, tracked)
+            })
+            .collect();
+        wallet.record_changes(&outputs);
+        wallet.history.outputs = outputs;
+    }
+
+    fn kinds(wallet: &Wallet) -> Vec<(HistoryKind, bool)> {
+        wallet
+            .history()
+            .iter()
+            .map(|entry| (entry.kind, entry.confirmed))
+            .collect()
+    }
+
+    #[test]
+    fn vanished_immature_output_is_forgotten() {
+        let mut wallet = wallet();
+        let (outpoint, output) = output();
+        refresh_with(&mut wallet, &[(outpoint, output, OutputState::Immature)]);
+        assert_eq!(kinds(&wallet), [(HistoryKind::Received, true)]);
+        // its block was reorganized away
+        refresh_with(&mut wallet, &[]);
+        assert!(wallet.history().is_empty());
+        assert_eq!(wallet.balance().immature, 0);
+    }
+
+    #[test]
+    fn vanished_confirmed_output_was_spent() {
+        let mut wallet = wallet();
+        let (outpoint, output) = output();
+        refresh_with(&mut wallet, &[(outpoint, output, OutputState::Confirmed)]);
+        refresh_with(&mut wallet, &[]);
+        assert_eq!(
+            kinds(&wallet),
+            [(HistoryKind::Received, true), (HistoryKind::Spent, true)]
+        );
+    }
+
+    #[test]
+    fn spend_is_confirmed_or_forgotten_with_its_transaction() {
+        let mut wallet = wallet();
+        let (outpoint, output) = output();
+        let confirmed = (outpoint, output.clone(), OutputState::Confirmed);
+        let spending = (outpoint, output, OutputState::Spending);
+        refresh_with(&mut wallet, std::slice::from_ref(&confirmed));
+        refresh_with(&mut wallet, std::slice::from_ref(&spending));
+        assert_eq!(
+            kinds(&wallet),
+            [(HistoryKind::Received, true), (HistoryKind::Spent, false)]
+        );
+        // the spending transaction was dropped from the mempool
+        refresh_with(&mut wallet, &[confirmed]);
+        assert_eq!(kinds(&wallet), [(HistoryKind::Received, true)]);
+        // and this time it confirmed
+        refresh_with(&mut wallet, &[spending]);
+        refresh_with(&mut wallet, &[]);
+        assert_eq!(
+            kinds(&wallet),
+            [(HistoryKind::Received, true), (HistoryKind::Spent, true)]
+        );
+    }
+
+    #[test]
+    fn dropped_unconfirmed_output_is_forgotten() {
+        let mut wallet = wallet();
+        let (outpoint, output) = output();
+        refresh_with(&mut wallet, &[(outpoint, output, OutputState::Unconfirmed)]);
+        assert_eq!(kinds(&wallet), [(HistoryKind::Received, false)]);
+        refresh_with(&mut wallet, &[]);
+        assert!(wallet.history().is_empty());
+    }
+}